@@ -1,16 +1,240 @@
+use std::cell::Cell;
+use std::collections::{BTreeMap, BTreeSet};
 use std::ffi::OsString;
+use std::fmt;
 use std::fs::read_dir;
 use std::fs::File;
-use std::path::Path;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
 
-use failure::Error;
-
-use flate2::read::GzDecoder;
-use tar::Archive;
+use bzip2::bufread::MultiBzDecoder;
+use bzip2::write::BzEncoder;
+use filetime::FileTime;
+use flate2::bufread::GzDecoder;
+use flate2::Compression;
+use flate2::GzBuilder;
+use glob::Pattern;
+use sha2::{Digest, Sha256};
+use tar::{Archive, Builder, Entry, EntryType, Header, HeaderMode};
+use url::Url;
+use xz2::bufread::XzDecoder;
+use xz2::write::XzEncoder;
 
+use crate::download::{stream, StreamOptions};
+use crate::error::{Error, IoContext};
+use crate::event::{Event, Observers};
 use crate::fs::mkdirp;
+use crate::manifest::{EntryKind, Manifest, ManifestEntry};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Codec {
+    Identity,
+    Gzip,
+    Xz,
+    Bzip2,
+    Zstd,
+}
+
+impl Codec {
+    /// From the first few bytes of a stream
+    pub fn detect(magic: &[u8]) -> Codec {
+        if magic.starts_with(&[0x1f, 0x8b]) {
+            Codec::Gzip
+        } else if magic.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Codec::Xz
+        } else if magic.starts_with(b"BZh") {
+            Codec::Bzip2
+        } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Codec::Zstd
+        } else {
+            Codec::Identity
+        }
+    }
+
+    /// `None` if the extension isn't one we unpack, e.g. `tool.gz` => `Gzip`, `x.tar` => `Identity`
+    pub fn from_extension(path: &Path) -> Option<Codec> {
+        match path.extension()?.to_str()? {
+            "tar" => Some(Codec::Identity),
+            "gz" | "tgz" => Some(Codec::Gzip),
+            "xz" | "txz" => Some(Codec::Xz),
+            "bz2" | "tbz" | "tbz2" => Some(Codec::Bzip2),
+            "zst" | "tzst" => Some(Codec::Zstd),
+            _ => None,
+        }
+    }
+
+    fn decoder<'a, R: BufRead + 'a>(self, reader: R) -> Result<Box<dyn Read + 'a>, Error> {
+        Ok(match self {
+            Codec::Identity => Box::new(reader),
+            Codec::Gzip => Box::new(GzDecoder::new(reader)),
+            Codec::Xz => Box::new(XzDecoder::new_multi_decoder(reader)),
+            Codec::Bzip2 => Box::new(MultiBzDecoder::new(reader)),
+            Codec::Zstd => Box::new(zstd::Decoder::with_buffer(reader)?),
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Tar,
+    TarGz,
+    TarXz,
+    TarBz2,
+    TarZst,
+}
+
+impl ArchiveFormat {
+    pub fn codec(self) -> Codec {
+        match self {
+            ArchiveFormat::Tar => Codec::Identity,
+            ArchiveFormat::TarGz => Codec::Gzip,
+            ArchiveFormat::TarXz => Codec::Xz,
+            ArchiveFormat::TarBz2 => Codec::Bzip2,
+            ArchiveFormat::TarZst => Codec::Zstd,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PackOptions {
+    pub format: ArchiveFormat,
+    /// 0 (store) through 9 (best), 1 through 9 for bzip2, or up to 22 for zstd; ignored for plain tar
+    pub compression_level: u32,
+    /// Glob patterns matched against each entry's path relative to the packed dir, and its name
+    pub exclude: Vec<String>,
+    /// Same tree, same bytes, on any machine: every entry gets `mtime`, uid
+    /// and gid 0, no user or group names, and mode 0755 (directories and
+    /// executables) or 0644
+    pub reproducible: bool,
+    /// Seconds since the epoch; only used when `reproducible`
+    pub mtime: u64,
+}
+
+impl Default for PackOptions {
+    fn default() -> Self {
+        PackOptions {
+            format: ArchiveFormat::TarGz,
+            compression_level: Compression::default().level(),
+            exclude: Vec::new(),
+            reproducible: false,
+            mtime: 0,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ExtractOptions {
+    /// Record the sha256 of every extracted file in the manifest
+    pub digest: bool,
+    pub limits: ExtractLimits,
+    /// Keep setuid, setgid and sticky bits from the archive; otherwise only
+    /// the rwx bits are applied
+    pub preserve_permissions: bool,
+    /// Mode bits cleared from every entry, like a umask
+    pub umask: u32,
+    pub ownership: Ownership,
+    pub mtime: Mtime,
+    /// Restore extended attributes (unix only). POSIX ACLs are carried as
+    /// `system.posix_acl_*` xattrs, so this covers them too.
+    pub xattrs: bool,
+    /// Told of `Event::ExtractFinished`
+    pub observers: Observers,
+    /// Leave entries that are already there alone, and out of the manifest
+    pub keep_existing: bool,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum Ownership {
+    /// Owned by whoever runs the extraction
+    #[default]
+    Current,
+    /// The uid/gid recorded in the archive; generally needs root
+    Preserve,
+    /// The archive's uid/gid looked up in these maps, e.g. `0 => 1000` to
+    /// give root owned entries to an unprivileged user. Unmapped ids are kept.
+    Remap {
+        uids: BTreeMap<u64, u32>,
+        gids: BTreeMap<u64, u32>,
+    },
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Mtime {
+    /// As recorded in the archive
+    #[default]
+    Archive,
+    /// Time of extraction
+    Now,
+    /// Seconds since the epoch
+    Fixed(u64),
+}
+
+/// Bounds on what an archive may expand to; `None` disables a check
+#[derive(Clone, Debug)]
+pub struct ExtractLimits {
+    pub max_total_bytes: Option<u64>,
+    pub max_file_bytes: Option<u64>,
+    pub max_entries: Option<u64>,
+    pub max_path_len: Option<usize>,
+    /// Uncompressed bytes per compressed byte
+    pub max_ratio: Option<u64>,
+}
 
-pub fn untar<D, E>(tarfile: D, extract_dir: Option<E>) -> Result<(), Error>
+impl Default for ExtractLimits {
+    fn default() -> Self {
+        ExtractLimits {
+            max_total_bytes: Some(64 << 30),
+            max_file_bytes: Some(16 << 30),
+            max_entries: Some(1_000_000),
+            max_path_len: Some(4096),
+            max_ratio: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ExtractLimitError {
+    TotalBytes(u64),
+    FileBytes(PathBuf, u64),
+    Entries(u64),
+    PathLength(PathBuf),
+    CompressionRatio(u64),
+}
+
+impl fmt::Display for ExtractLimitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ExtractLimitError::TotalBytes(max) => {
+                write!(f, "archive expands to more than {} bytes", max)
+            }
+            ExtractLimitError::FileBytes(path, max) => {
+                write!(f, "{:?} is larger than {} bytes", path, max)
+            }
+            ExtractLimitError::Entries(max) => write!(f, "archive has more than {} entries", max),
+            ExtractLimitError::PathLength(path) => write!(f, "path too long: {:?}", path),
+            ExtractLimitError::CompressionRatio(max) => {
+                write!(f, "archive compression ratio exceeds {}:1", max)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ExtractLimitError {}
+
+pub fn untar<D, E>(tarfile: D, extract_dir: Option<E>) -> Result<Manifest, Error>
+where
+    D: Into<OsString>,
+    E: Into<OsString>,
+{
+    untar_with_options(tarfile, extract_dir, &ExtractOptions::default())
+}
+
+pub fn untar_with_options<D, E>(
+    tarfile: D,
+    extract_dir: Option<E>,
+    options: &ExtractOptions,
+) -> Result<Manifest, Error>
 where
     D: Into<OsString>,
     E: Into<OsString>,
@@ -25,55 +249,974 @@ where
     mkdirp(&extract_to)?;
     match &Path::new(&tfile).parent() {
         Some(parent) => Ok(mkdirp(parent)?),
-        None => Err(format_err!("no parent found for {:?}", &tfile)),
+        None => Err(Error::invalid_path(&tfile, "no parent found")),
+    }?;
+
+    let manifest = untar_reader(
+        File::open(&tfile).at(&tfile)?,
+        Path::new(&extract_to),
+        options,
+    )?;
+    Ok(extracted(&tfile.to_string_lossy(), manifest, options))
+}
+
+/// Tells the observers of `options` about `manifest`, and returns it
+pub(crate) fn extracted(archive: &str, manifest: Manifest, options: &ExtractOptions) -> Manifest {
+    options.observers.notify(&Event::ExtractFinished {
+        archive,
+        manifest: &manifest,
+    });
+    manifest
+}
+
+/// Extracts a tarball, plain or compressed with gzip, xz, bzip2 or zstd, as
+/// it downloads, without saving it first unless `stream_options.keep_copy`
+/// asks for it. A body that fails `stream_options.sha256` leaves the extract
+/// dir as it was: files it created are removed again, and files it wrote
+/// over are put back.
+pub fn untar_url<E>(
+    url: &Url,
+    extract_dir: Option<E>,
+    options: &ExtractOptions,
+    stream_options: &StreamOptions,
+) -> Result<Manifest, Error>
+where
+    E: Into<OsString>,
+{
+    let extract_to = match extract_dir {
+        Some(d) => d.into(),
+        None => OsString::from("."),
+    };
+    mkdirp(&extract_to)?;
+
+    if let Some(cached) = stream_options.cached_copy()? {
+        return untar_with_options(cached, Some(extract_to), options);
+    }
+
+    let mut body = stream(url, stream_options)?;
+    let (manifest, backup) = untar_reader_backed_up(&mut body, Path::new(&extract_to), options)?;
+    if let Err(e) = body.finish() {
+        manifest.remove()?;
+        backup.restore(&manifest.root)?;
+        return Err(e);
+    }
+    Ok(extracted(url.as_str(), manifest, options))
+}
+
+/// Writes the decompressed contents of a single compressed file (not a
+/// tarball) such as `tool-linux-amd64.gz` to `dest`
+pub fn decompress<F, D>(file: F, dest: D) -> Result<Manifest, Error>
+where
+    F: Into<OsString>,
+    D: Into<OsString>,
+{
+    decompress_with_options(file, dest, &ExtractOptions::default())
+}
+
+pub fn decompress_with_options<F, D>(
+    file: F,
+    dest: D,
+    options: &ExtractOptions,
+) -> Result<Manifest, Error>
+where
+    F: Into<OsString>,
+    D: Into<OsString>,
+{
+    let src = file.into();
+    let out = PathBuf::from(dest.into());
+    let name = match out.file_name() {
+        Some(name) => Ok(PathBuf::from(name)),
+        None => Err(Error::invalid_path(&out, "no filename found")),
     }?;
+    let root = match out.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => {
+            mkdirp(parent)?;
+            parent.canonicalize().at(parent)?
+        }
+        _ => std::env::current_dir()?,
+    };
+
+    let limits = &options.limits;
+    let mut reader = decompressing(File::open(&src).at(&src)?, limits)?;
+    let tripped = reader.tripped.clone();
+    let cap = match (limits.max_file_bytes, limits.max_total_bytes) {
+        (Some(file_max), Some(total_max)) => Some(file_max.min(total_max)),
+        (file_max, total_max) => file_max.or(total_max),
+    };
+
+    let existed = root.join(&name).symlink_metadata().is_ok();
+    let written = File::create(root.join(&name)).and_then(|mut f| match cap {
+        Some(cap) => io::copy(&mut (&mut reader).take(cap + 1), &mut f),
+        None => io::copy(&mut reader, &mut f),
+    });
+    let result = match (written, cap) {
+        (Ok(n), Some(cap)) if n > cap => {
+            Err(ExtractLimitError::FileBytes(name.clone(), cap).into())
+        }
+        (Ok(_), _) => Ok(()),
+        (Err(e), _) => Err(limit_error(&tripped, limits, e.into())),
+    };
+    if let Err(e) = result {
+        if !existed {
+            let _ = std::fs::remove_file(root.join(&name));
+        }
+        return Err(e);
+    }
+
+    let mut written = Written::default();
+    if existed {
+        written.replaced.insert(name.clone());
+    }
+    written.created.insert(name);
+    let manifest = written.manifest(root, options.digest)?;
+    Ok(extracted(&src.to_string_lossy(), manifest, options))
+}
+
+/// Whether `file`, once decompressed, starts with a valid tar header
+pub fn is_tarball<F>(file: F) -> Result<bool, Error>
+where
+    F: Into<OsString>,
+{
+    let limits = ExtractLimits {
+        max_ratio: None,
+        ..ExtractLimits::default()
+    };
+    let file = file.into();
+    let mut block = Vec::with_capacity(512);
+    decompressing(File::open(&file).at(&file)?, &limits)?
+        .take(512)
+        .read_to_end(&mut block)
+        .at(&file)?;
+    Ok(is_tar_header(&block))
+}
+
+fn is_tar_header(block: &[u8]) -> bool {
+    if block.len() < 512 {
+        return false;
+    }
+    if &block[257..262] == b"ustar" {
+        return true;
+    }
+    // Pre-POSIX tars have no magic, so fall back to the header checksum
+    let stored = std::str::from_utf8(&block[148..156])
+        .ok()
+        .map(|s| s.trim_matches(|c: char| c == '\0' || c == ' '))
+        .and_then(|s| u32::from_str_radix(s, 8).ok());
+    let sum: u32 = block
+        .iter()
+        .enumerate()
+        .map(|(i, b)| {
+            if (148..156).contains(&i) {
+                32
+            } else {
+                u32::from(*b)
+            }
+        })
+        .sum();
+    stored == Some(sum)
+}
+
+pub(crate) fn untar_reader<R: Read>(
+    reader: R,
+    dest: &Path,
+    options: &ExtractOptions,
+) -> Result<Manifest, Error> {
+    untar_reader_backed_up(reader, dest, options).map(|(manifest, _)| manifest)
+}
+
+fn untar_reader_backed_up<R: Read>(
+    reader: R,
+    dest: &Path,
+    options: &ExtractOptions,
+) -> Result<(Manifest, Backup), Error> {
+    let reader = decompressing(reader, &options.limits)?;
+    let tripped = reader.tripped.clone();
+    unpack_backed_up(&mut Archive::new(reader), dest, options)
+        .map_err(|e| limit_error(&tripped, &options.limits, e))
+}
+
+pub(crate) fn decompressing<'a, R: Read + 'a>(
+    reader: R,
+    limits: &ExtractLimits,
+) -> Result<RatioGuard<Box<dyn Read + 'a>>, Error> {
+    let compressed = Rc::new(Cell::new(0));
+    let mut reader = BufReader::new(CountingReader {
+        inner: reader,
+        count: compressed.clone(),
+    });
+    let codec = Codec::detect(reader.fill_buf()?);
+    Ok(RatioGuard {
+        inner: codec.decoder(reader)?,
+        compressed,
+        uncompressed: 0,
+        max_ratio: limits.max_ratio,
+        tripped: Rc::new(Cell::new(false)),
+    })
+}
+
+/// The I/O error a tripped `RatioGuard` causes, as the limit it enforces
+pub(crate) fn limit_error(tripped: &Cell<bool>, limits: &ExtractLimits, e: Error) -> Error {
+    match limits.max_ratio {
+        Some(max) if tripped.get() => ExtractLimitError::CompressionRatio(max).into(),
+        _ => e,
+    }
+}
+
+struct CountingReader<R> {
+    inner: R,
+    count: Rc<Cell<u64>>,
+}
+
+impl<R: Read> Read for CountingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count.set(self.count.get() + n as u64);
+        Ok(n)
+    }
+}
+
+// Below this much output the ratio says more about headers and buffering than the archive
+const RATIO_GRACE_BYTES: u64 = 1 << 20;
+
+/// Fails the decompressed stream as soon as it outgrows `max_ratio` times
+/// the compressed bytes consumed so far
+pub(crate) struct RatioGuard<R> {
+    inner: R,
+    compressed: Rc<Cell<u64>>,
+    uncompressed: u64,
+    max_ratio: Option<u64>,
+    pub(crate) tripped: Rc<Cell<bool>>,
+}
+
+impl<R: Read> Read for RatioGuard<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.uncompressed += n as u64;
+        if let Some(max) = self.max_ratio {
+            if self.uncompressed > RATIO_GRACE_BYTES
+                && self.uncompressed / self.compressed.get().max(1) > max
+            {
+                self.tripped.set(true);
+                return Err(io::Error::other("compression ratio limit exceeded"));
+            }
+        }
+        Ok(n)
+    }
+}
+
+pub(crate) fn unpack<R: Read>(
+    archive: &mut Archive<R>,
+    dest: &Path,
+    options: &ExtractOptions,
+) -> Result<Manifest, Error> {
+    unpack_backed_up(archive, dest, options).map(|(manifest, _)| manifest)
+}
+
+/// `unpack`, also returning the files it wrote over, for callers that only
+/// find out afterwards whether what they extracted can be kept
+fn unpack_backed_up<R: Read>(
+    archive: &mut Archive<R>,
+    dest: &Path,
+    options: &ExtractOptions,
+) -> Result<(Manifest, Backup), Error> {
+    let root = dest.canonicalize().at(dest)?;
+    let mut written = Written::default();
+
+    archive.set_preserve_permissions(options.preserve_permissions);
+    archive.set_mask(options.umask);
+    archive.set_preserve_ownerships(options.ownership == Ownership::Preserve);
+    archive.set_preserve_mtime(options.mtime == Mtime::Archive);
+    archive.set_unpack_xattrs(options.xattrs);
+
+    match unpack_all(archive, &root, options, &mut written) {
+        Ok(()) => Ok((written.manifest(&root, options.digest)?, written.backup)),
+        Err(e) => {
+            // Don't leave a half extracted tree behind, and put back what
+            // was there before
+            written
+                .created
+                .retain(|rel| root.join(rel).symlink_metadata().is_ok());
+            if let Ok(manifest) = written.manifest(&root, false) {
+                let _ = manifest.remove();
+            }
+            let _ = written.backup.restore(&root);
+            Err(e)
+        }
+    }
+}
+
+/// Files an extraction moved aside before writing over them, so that they
+/// can be put back if what it wrote turns out to be bad. Dropping it drops
+/// the saved files.
+#[derive(Default)]
+pub(crate) struct Backup {
+    dir: Option<tempfile::TempDir>,
+    /// Relative to the extraction root, in the order they were saved
+    saved: Vec<PathBuf>,
+}
+
+impl Backup {
+    /// Moves `root/rel` into a hidden dir under `root`, so it stays on the
+    /// same filesystem
+    fn save(&mut self, root: &Path, rel: &Path) -> Result<(), Error> {
+        if self.dir.is_none() {
+            let dir = tempfile::Builder::new()
+                .prefix(".offregisters-backup")
+                .tempdir_in(root)
+                .at(root)?;
+            self.dir = Some(dir);
+        }
+        let from = root.join(rel);
+        if let Some(dir) = &self.dir {
+            std::fs::rename(&from, dir.path().join(self.saved.len().to_string())).at(&from)?;
+        }
+        self.saved.push(rel.to_path_buf());
+        Ok(())
+    }
+
+    /// Puts every saved file back, over whatever is there now
+    pub(crate) fn restore(self, root: &Path) -> Result<(), Error> {
+        let dir = match &self.dir {
+            Some(dir) => dir.path(),
+            None => return Ok(()),
+        };
+        for (i, rel) in self.saved.iter().enumerate() {
+            let path = root.join(rel);
+            match std::fs::remove_file(&path) {
+                Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e).at(&path),
+                _ => {}
+            }
+            std::fs::rename(dir.join(i.to_string()), &path).at(&path)?;
+        }
+        Ok(())
+    }
+}
+
+/// What an extraction wrote, relative to its root, and which of those paths
+/// it overwrote rather than created
+#[derive(Default)]
+struct Written {
+    created: BTreeSet<PathBuf>,
+    replaced: BTreeSet<PathBuf>,
+    backup: Backup,
+}
+
+impl Written {
+    fn manifest<R: Into<OsString>>(&self, root: R, digest: bool) -> Result<Manifest, Error> {
+        let mut manifest = Manifest::from_paths(root, &self.created, digest)?;
+        for entry in &mut manifest.entries {
+            entry.replaced = self.replaced.contains(&entry.path);
+        }
+        Ok(manifest)
+    }
+}
+
+fn unpack_all<R: Read>(
+    archive: &mut Archive<R>,
+    root: &Path,
+    options: &ExtractOptions,
+    written: &mut Written,
+) -> Result<(), Error> {
+    let limits = &options.limits;
+    let mut entries: u64 = 0;
+    let mut total_bytes: u64 = 0;
+    let mut directories = Vec::new();
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+
+        entries += 1;
+        total_bytes += entry.size();
+        check_limits(&entry, limits, entries, total_bytes)?;
+
+        if entry.header().entry_type() == EntryType::Directory {
+            directories.push(entry);
+        } else {
+            unpack_entry(&mut entry, root, options, written)?;
+        }
+    }
+
+    // Like `Archive::unpack`: directories last, deepest first, so a read-only
+    // directory doesn't stop its own contents from being written
+    directories.sort_by(|a, b| b.path_bytes().cmp(&a.path_bytes()));
+    for mut dir in directories {
+        unpack_entry(&mut dir, root, options, written)?;
+    }
+    Ok(())
+}
+
+// Sizes come from the headers, which bound what unpacking will write
+fn check_limits<R: Read>(
+    entry: &Entry<R>,
+    limits: &ExtractLimits,
+    entries: u64,
+    total_bytes: u64,
+) -> Result<(), Error> {
+    if let Some(max) = limits.max_entries.filter(|max| entries > *max) {
+        return Err(ExtractLimitError::Entries(max).into());
+    }
+    if limits
+        .max_path_len
+        .is_some_and(|max| entry.path_bytes().len() > max)
+    {
+        return Err(ExtractLimitError::PathLength(entry.path()?.into_owned()).into());
+    }
+    if let Some(max) = limits.max_file_bytes.filter(|max| entry.size() > *max) {
+        return Err(ExtractLimitError::FileBytes(entry.path()?.into_owned(), max).into());
+    }
+    if let Some(max) = limits.max_total_bytes.filter(|max| total_bytes > *max) {
+        return Err(ExtractLimitError::TotalBytes(max).into());
+    }
+    Ok(())
+}
+
+fn unpack_entry<R: Read>(
+    entry: &mut Entry<R>,
+    root: &Path,
+    options: &ExtractOptions,
+    written: &mut Written,
+) -> Result<(), Error> {
+    let rel = sanitized_path(entry)?;
+    let path = root.join(&rel);
+    let existing = path.symlink_metadata().ok();
+    let existed = existing.is_some();
+    if options.keep_existing && existed {
+        return Ok(());
+    }
+
+    // Parent directories the archive doesn't list, but unpacking will create
+    let missing_parents: Vec<PathBuf> = rel
+        .ancestors()
+        .skip(1)
+        .filter(|a| !a.as_os_str().is_empty() && !root.join(a).exists())
+        .map(Path::to_path_buf)
+        .collect();
+
+    let replacing = existed && !written.created.contains(&rel);
+    if replacing && existing.is_some_and(|m| !m.is_dir()) {
+        written.backup.save(root, &rel)?;
+    }
+
+    // Recorded on failure too, so a partially written entry gets cleaned up
+    let unpacked = entry.unpack_in(root);
+    if !matches!(unpacked, Ok(false)) && !rel.as_os_str().is_empty() {
+        if replacing {
+            written.replaced.insert(rel.clone());
+        }
+        written.created.extend(missing_parents);
+        written.created.insert(rel.clone());
+    }
+    if unpacked.at(&path)? && !rel.as_os_str().is_empty() {
+        apply_metadata(entry, &path, options)?;
+    }
+    Ok(())
+}
+
+/// Where `entry` lands relative to the extract dir: `..`, `/` and `.` dropped
+fn sanitized_path<R: Read>(entry: &Entry<R>) -> Result<PathBuf, Error> {
+    Ok(entry
+        .path()?
+        .components()
+        .filter(|c| matches!(c, Component::Normal(_)))
+        .collect())
+}
+
+// What `tar` can't do for us: remapped owners and non-archive mtimes
+fn apply_metadata<R: Read>(
+    entry: &Entry<R>,
+    path: &Path,
+    options: &ExtractOptions,
+) -> Result<(), Error> {
+    let header = entry.header();
+
+    #[cfg(unix)]
+    {
+        if let Ownership::Remap { uids, gids } = &options.ownership {
+            use std::os::unix::fs::{lchown, PermissionsExt};
 
-    let tar_gz = File::open(&tfile)?;
-    let tar = GzDecoder::new(tar_gz);
+            let (uid, gid) = (header.uid().at(path)?, header.gid().at(path)?);
+            let uid = uids.get(&uid).copied().unwrap_or(uid as u32);
+            let gid = gids.get(&gid).copied().unwrap_or(gid as u32);
+            lchown(path, Some(uid), Some(gid)).at(path)?;
 
-    let mut archive = Archive::new(tar);
+            // chown clears setuid/setgid, so put the mode back
+            if !header.entry_type().is_symlink() {
+                let keep = if options.preserve_permissions {
+                    0o7777
+                } else {
+                    0o777
+                };
+                let mode = header.mode().at(path)? & keep & !options.umask;
+                std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode)).at(path)?;
+            }
+        }
+    }
 
-    archive.unpack(&extract_to)?;
+    let mtime = match options.mtime {
+        Mtime::Archive => return Ok(()),
+        Mtime::Now => FileTime::now(),
+        Mtime::Fixed(secs) => FileTime::from_unix_time(secs as i64, 0),
+    };
+    filetime::set_symlink_file_times(path, mtime, mtime).at(path)?;
     Ok(())
 }
 
-pub fn untar_all_in_dir<D, E>(input_dir: D, extract_dir: Option<E>) -> Result<(), Error>
+#[derive(Clone, Debug, Default)]
+pub struct UntarAllOptions {
+    /// Also look in subdirectories of `input_dir`
+    pub recursive: bool,
+    /// Extract each tarball into `<extract_dir>/<stem>`, e.g. `pg-12.tar.gz`
+    /// into `pg-12/`, keeping the layout of nested input dirs
+    pub subdirs: bool,
+    pub extract: ExtractOptions,
+}
+
+#[derive(Debug, Default)]
+pub struct UntarAllReport {
+    /// Each archive, and what it produced
+    pub extracted: Vec<(PathBuf, Manifest)>,
+    /// Files that aren't archives
+    pub skipped: Vec<PathBuf>,
+    pub failed: Vec<(PathBuf, Error)>,
+}
+
+/// Two archives in one `untar_all_in_dir` would write the same file
+#[derive(Debug)]
+pub struct ArchiveCollisionError {
+    /// Relative to the extract dir
+    pub path: PathBuf,
+    pub archive: PathBuf,
+    /// The earlier archive that already claimed `path`
+    pub other: PathBuf,
+}
+
+impl fmt::Display for ArchiveCollisionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:?} from {:?} collides with the one from {:?}",
+            self.path, self.archive, self.other
+        )
+    }
+}
+
+impl std::error::Error for ArchiveCollisionError {}
+
+/// Extracts every archive in `input_dir`. A failing archive doesn't stop the
+/// rest; check `UntarAllReport::failed`.
+pub fn untar_all_in_dir<D, E>(input_dir: D, extract_dir: Option<E>) -> Result<UntarAllReport, Error>
+where
+    D: Into<OsString>,
+    E: Into<OsString>,
+{
+    untar_all_in_dir_with_options(input_dir, extract_dir, &UntarAllOptions::default())
+}
+
+pub fn untar_all_in_dir_with_options<D, E>(
+    input_dir: D,
+    extract_dir: Option<E>,
+    options: &UntarAllOptions,
+) -> Result<UntarAllReport, Error>
 where
     D: Into<OsString>,
     E: Into<OsString>,
 {
-    let input_d = input_dir.into();
+    let input_d = PathBuf::from(input_dir.into());
     let extract_d = match extract_dir {
-        Some(d) => d.into(),
+        Some(d) => PathBuf::from(d.into()),
         None => input_d.clone(),
     };
 
     // Good practice? - Should we create the dir, or is that callers responsibility?
     mkdirp(&extract_d)?;
 
-    for path in read_dir(&input_d)? {
-        let p = path?.path();
-        let _ = match p.extension() {
-            Some(ext) => {
-                if ext == "gz" {
-                    untar(p, Some(&extract_d))?;
-                    Some(())
-                } else {
-                    None
-                }
+    // Listed up front, so nothing we extract gets picked up as input
+    let mut files = Vec::new();
+    collect_files(
+        &input_d,
+        &extract_d.canonicalize().at(&extract_d)?,
+        options.recursive,
+        &mut files,
+    )?;
+    files.sort();
+
+    let mut report = UntarAllReport::default();
+    let mut claimed: BTreeMap<PathBuf, PathBuf> = BTreeMap::new();
+    for file in files {
+        let rel_dir = match file.parent().map(|p| p.strip_prefix(&input_d)) {
+            Some(Ok(rel_dir)) if options.subdirs => rel_dir.to_path_buf(),
+            _ => PathBuf::new(),
+        };
+        match untar_one(&file, &extract_d, &rel_dir, options, &mut claimed) {
+            Ok(Some(manifest)) => report.extracted.push((file, manifest)),
+            Ok(None) => report.skipped.push(file),
+            Err(e) => report.failed.push((file, e)),
+        }
+    }
+    Ok(report)
+}
+
+fn collect_files(
+    dir: &Path,
+    skip: &Path,
+    recursive: bool,
+    files: &mut Vec<PathBuf>,
+) -> Result<(), Error> {
+    for entry in read_dir(dir).at(dir)? {
+        let entry = entry.at(dir)?;
+        let p = entry.path();
+        // Not following symlinked dirs, which could loop
+        if entry.file_type().at(&p)?.is_dir() {
+            if recursive && p.canonicalize().at(&p)? != skip {
+                collect_files(&p, skip, recursive, files)?;
             }
-            None => None,
+        } else if p.is_file() {
+            files.push(p);
+        }
+    }
+    Ok(())
+}
+
+fn untar_one(
+    file: &Path,
+    extract_d: &Path,
+    rel_dir: &Path,
+    options: &UntarAllOptions,
+    claimed: &mut BTreeMap<PathBuf, PathBuf>,
+) -> Result<Option<Manifest>, Error> {
+    let codec = match Codec::from_extension(file) {
+        Some(codec) => codec,
+        None => return Ok(None),
+    };
+    let tarball = is_tarball(file)?;
+    if !tarball && codec == Codec::Identity {
+        return Ok(None);
+    }
+    let stem = archive_stem(file)?;
+
+    // Everything this archive would write, relative to `extract_d`
+    let (dest, paths) = if tarball {
+        let dest = if options.subdirs {
+            rel_dir.join(&stem)
+        } else {
+            PathBuf::new()
+        };
+        let listing = ExtractOptions {
+            digest: false,
+            ..options.extract.clone()
+        };
+        let paths = list_with_options(file, &listing)?
+            .into_iter()
+            .filter(|e| e.kind != EntryKind::Directory)
+            .map(|e| e.path)
+            .collect();
+        (dest, paths)
+    } else {
+        // A bare compressed file, e.g. `tool-linux-amd64.gz`
+        (rel_dir.to_path_buf(), vec![stem])
+    };
+    let paths: Vec<PathBuf> = paths.iter().map(|p| dest.join(p)).collect();
+
+    if let Some((path, other)) = paths
+        .iter()
+        .find_map(|p| claimed.get(p).map(|other| (p, other)))
+    {
+        return Err(ArchiveCollisionError {
+            path: path.clone(),
+            archive: file.to_path_buf(),
+            other: other.clone(),
+        }
+        .into());
+    }
+
+    let dest = extract_d.join(dest);
+    let manifest = if tarball {
+        untar_with_options(file, Some(&dest), &options.extract)?
+    } else {
+        decompress_with_options(file, extract_d.join(&paths[0]), &options.extract)?
+    };
+    // Only once extracted, so a failed archive doesn't collide with later ones
+    for path in paths {
+        claimed.insert(path, file.to_path_buf());
+    }
+    Ok(Some(manifest))
+}
+
+/// `pg-12.tar.gz`, `pg-12.tgz` and `pg-12.gz` are all `pg-12`
+fn archive_stem(file: &Path) -> Result<PathBuf, Error> {
+    let stem = match file.file_stem() {
+        Some(stem) => Ok(Path::new(stem)),
+        None => Err(Error::invalid_path(file, "no filename found")),
+    }?;
+    Ok(match (stem.extension(), stem.file_stem()) {
+        (Some(ext), Some(inner)) if ext == "tar" => PathBuf::from(inner),
+        _ => stem.to_path_buf(),
+    })
+}
+
+/// The entries of a tarball, streamed once without writing anything. The
+/// limits of `options` apply, and digests are taken if asked for.
+pub fn list<F>(tarfile: F) -> Result<Vec<ManifestEntry>, Error>
+where
+    F: Into<OsString>,
+{
+    list_with_options(tarfile, &ExtractOptions::default())
+}
+
+pub fn list_with_options<F>(
+    tarfile: F,
+    options: &ExtractOptions,
+) -> Result<Vec<ManifestEntry>, Error>
+where
+    F: Into<OsString>,
+{
+    let tarfile = tarfile.into();
+    let reader = decompressing(File::open(&tarfile).at(&tarfile)?, &options.limits)?;
+    let tripped = reader.tripped.clone();
+    list_reader(reader, options).map_err(|e| limit_error(&tripped, &options.limits, e))
+}
+
+fn list_reader<R: Read>(reader: R, options: &ExtractOptions) -> Result<Vec<ManifestEntry>, Error> {
+    let mut archive = Archive::new(reader);
+    let mut listed = Vec::new();
+    let mut entries: u64 = 0;
+    let mut total_bytes: u64 = 0;
+
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+
+        entries += 1;
+        total_bytes += entry.size();
+        check_limits(&entry, &options.limits, entries, total_bytes)?;
+
+        let path = sanitized_path(&entry)?;
+        if path.as_os_str().is_empty() {
+            continue;
+        }
+        let kind = match entry.header().entry_type() {
+            EntryType::Regular | EntryType::Continuous => EntryKind::File,
+            EntryType::Directory => EntryKind::Directory,
+            EntryType::Symlink => EntryKind::Symlink,
+            _ => EntryKind::Other,
+        };
+        let mode = entry.header().mode()? & 0o7777;
+        let is_file = kind == EntryKind::File;
+        let digest = if options.digest && is_file {
+            let mut hasher = Sha256::new();
+            io::copy(&mut entry, &mut hasher)?;
+            Some(format!("{:x}", hasher.result()))
+        } else {
+            None
         };
+        listed.push(ManifestEntry {
+            path,
+            kind,
+            size: if is_file { entry.size() } else { 0 },
+            mode,
+            digest,
+            replaced: false,
+        });
+    }
+
+    // tar stops at its end marker; reading on to the end of the compressed
+    // stream is what checks its trailer (gzip CRC and the like)
+    io::copy(&mut archive.into_inner(), &mut io::sink())?;
+    Ok(listed)
+}
+
+#[derive(Debug)]
+pub struct MissingEntriesError {
+    pub missing: Vec<PathBuf>,
+}
+
+impl fmt::Display for MissingEntriesError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "archive is missing {:?}", self.missing)
+    }
+}
+
+impl std::error::Error for MissingEntriesError {}
+
+/// Like `list`, and fails with `MissingEntriesError` unless every one of
+/// `expected` (e.g. `bin/postgres`) is in the archive. Tar header checksums
+/// and the compressed stream's own checksum are validated along the way.
+pub fn verify<F, P>(tarfile: F, expected: &[P]) -> Result<Vec<ManifestEntry>, Error>
+where
+    F: Into<OsString>,
+    P: AsRef<Path>,
+{
+    let listed = list(tarfile)?;
+    let present: BTreeSet<&Path> = listed.iter().map(|e| e.path.as_path()).collect();
+    let missing: Vec<PathBuf> = expected
+        .iter()
+        .map(|p| {
+            p.as_ref()
+                .components()
+                .filter(|c| matches!(c, Component::Normal(_)))
+                .collect::<PathBuf>()
+        })
+        .filter(|p| !present.contains(p.as_path()))
+        .collect();
+    if !missing.is_empty() {
+        return Err(MissingEntriesError { missing }.into());
+    }
+    Ok(listed)
+}
+
+pub fn pack<D, O>(dir: D, output: O, options: &PackOptions) -> Result<(), Error>
+where
+    D: Into<OsString>,
+    O: Into<OsString>,
+{
+    let src = dir.into();
+    let out = PathBuf::from(output.into());
+    let exclude = options
+        .exclude
+        .iter()
+        .map(|pattern| Pattern::new(pattern))
+        .collect::<Result<Vec<Pattern>, _>>()?;
+
+    let root = Path::new(&src).canonicalize().at(&src)?;
+    let out_dir = match out.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => {
+            mkdirp(parent)?;
+            parent.canonicalize().at(parent)?
+        }
+        _ => std::env::current_dir()?,
+    };
+    let out_name = match out.file_name() {
+        Some(name) => Ok(name),
+        None => Err(Error::invalid_path(&out, "no filename found")),
+    }?;
+
+    // Sorted so that the same tree always produces the same archive
+    let mut entries: Vec<PathBuf> = Vec::new();
+    collect_entries(
+        &root,
+        &root,
+        &exclude,
+        &out_dir.join(out_name),
+        &mut entries,
+    )?;
+    entries.sort();
+
+    let level = options.compression_level;
+    let (min_level, max_level) = match options.format.codec() {
+        Codec::Identity => (0, u32::MAX),
+        Codec::Bzip2 => (1, 9),
+        Codec::Zstd => (0, 22),
+        _ => (0, 9),
+    };
+    if !(min_level..=max_level).contains(&level) {
+        return Err(Error::archive(
+            &out,
+            format!(
+                "compression level {} is outside {}..={} for {:?}",
+                level, min_level, max_level, options.format
+            ),
+        ));
     }
+
+    let file = File::create(&out).at(&out)?;
+    match options.format.codec() {
+        Codec::Identity => {
+            append_entries(Builder::new(file), &root, &entries, options)?;
+        }
+        Codec::Gzip => {
+            // No name or mtime, and OS "unknown", so the gzip header is fixed too
+            let enc = GzBuilder::new()
+                .operating_system(255)
+                .write(file, Compression::new(level));
+            append_entries(Builder::new(enc), &root, &entries, options)?.finish()?;
+        }
+        Codec::Xz => {
+            let enc = XzEncoder::new(file, level);
+            append_entries(Builder::new(enc), &root, &entries, options)?.finish()?;
+        }
+        Codec::Bzip2 => {
+            let enc = BzEncoder::new(file, bzip2::Compression::new(level));
+            append_entries(Builder::new(enc), &root, &entries, options)?.finish()?;
+        }
+        Codec::Zstd => {
+            let enc = zstd::Encoder::new(file, level as i32)?;
+            append_entries(Builder::new(enc), &root, &entries, options)?.finish()?;
+        }
+    };
     Ok(())
 }
 
+fn collect_entries(
+    root: &Path,
+    dir: &Path,
+    exclude: &[Pattern],
+    skip: &Path,
+    entries: &mut Vec<PathBuf>,
+) -> Result<(), Error> {
+    for entry in read_dir(dir).at(dir)? {
+        let entry = entry.at(dir)?;
+        let path = entry.path();
+        let rel = path
+            .strip_prefix(root)
+            .map_err(|e| Error::invalid_path(&path, e.to_string()))?
+            .to_path_buf();
+        if path == skip || is_excluded(&rel, exclude) {
+            continue;
+        }
+        let is_dir = entry.file_type().at(&path)?.is_dir();
+        entries.push(rel);
+        if is_dir {
+            collect_entries(root, &path, exclude, skip, entries)?;
+        }
+    }
+    Ok(())
+}
+
+fn is_excluded(rel: &Path, exclude: &[Pattern]) -> bool {
+    exclude.iter().any(|pattern| {
+        pattern.matches_path(rel)
+            || rel
+                .file_name()
+                .is_some_and(|name| pattern.matches(&name.to_string_lossy()))
+    })
+}
+
+fn append_entries<W: Write>(
+    mut builder: Builder<W>,
+    root: &Path,
+    entries: &[PathBuf],
+    options: &PackOptions,
+) -> Result<W, Error> {
+    builder.follow_symlinks(false);
+    for rel in entries {
+        let path = root.join(rel);
+        if !options.reproducible {
+            builder.append_path_with_name(&path, rel)?;
+            continue;
+        }
+
+        let metadata = path.symlink_metadata().at(&path)?;
+        let mut header = Header::new_gnu();
+        header.set_metadata_in_mode(&metadata, HeaderMode::Deterministic);
+        header.set_mtime(options.mtime);
+        if metadata.file_type().is_symlink() {
+            builder.append_link(&mut header, rel, std::fs::read_link(&path).at(&path)?)?;
+        } else if metadata.is_file() {
+            builder.append_data(&mut header, rel, File::open(&path)?)?;
+        } else {
+            builder.append_data(&mut header, rel, io::empty())?;
+        }
+    }
+    Ok(builder.into_inner()?)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
     use flate2::write::GzEncoder;
-    use flate2::Compression;
+
+    use crate::download::serve_once;
+    use crate::fs::sha256sum;
     use tempfile::TempDir;
 
     fn tar<D>(tarfile: D)
@@ -94,7 +1237,7 @@ mod tests {
             .tempdir()
             .unwrap();
 
-        let tmp_dir = _tmp_dir.into_path().join("test_untar");
+        let tmp_dir = _tmp_dir.path().join("test_untar");
         mkdirp(&tmp_dir).unwrap();
 
         let tarfile = tmp_dir.join("example.tar.gz");
@@ -103,7 +1246,240 @@ mod tests {
         let untar_directory = tmp_dir.join("untar");
         untar(&tarfile, Some(&untar_directory)).unwrap();
 
-        assert_eq!(untar_directory.join(file!()).exists(), true);
+        assert!(untar_directory.join(file!()).exists());
+    }
+
+    #[test]
+    fn test_untar_manifest() {
+        let tmp_dir: TempDir = tempfile::Builder::new()
+            .prefix(env!("CARGO_PKG_NAME"))
+            .tempdir()
+            .unwrap();
+        let tarfile = tmp_dir.path().join("src.tar.gz");
+        pack(
+            pack_fixture(tmp_dir.path()),
+            &tarfile,
+            &PackOptions::default(),
+        )
+        .unwrap();
+
+        let untar_directory = tmp_dir.path().join("untar");
+        let options = ExtractOptions {
+            digest: true,
+            ..ExtractOptions::default()
+        };
+        let manifest = untar_with_options(&tarfile, Some(&untar_directory), &options).unwrap();
+
+        let paths: Vec<&Path> = manifest.entries.iter().map(|e| e.path.as_path()).collect();
+        assert_eq!(
+            paths,
+            vec![
+                Path::new("a.txt"),
+                Path::new("sub"),
+                Path::new("sub/b.txt"),
+                Path::new("sub/skip.log")
+            ]
+        );
+        let b = &manifest.entries[2];
+        assert_eq!(b.kind, EntryKind::File);
+        assert_eq!(b.size, 1);
+        assert_eq!(
+            b.digest,
+            Some(sha256sum(untar_directory.join("sub").join("b.txt")).unwrap())
+        );
+
+        let manifest_path = tmp_dir.path().join("manifest.json");
+        manifest.save(&manifest_path).unwrap();
+        Manifest::load(&manifest_path).unwrap().remove().unwrap();
+        assert_eq!(read_dir(&untar_directory).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn test_untar_url() {
+        let tmp_dir: TempDir = tempfile::Builder::new()
+            .prefix(env!("CARGO_PKG_NAME"))
+            .tempdir()
+            .unwrap();
+        let tarfile = tmp_dir.path().join("src.tar.gz");
+        pack(
+            pack_fixture(tmp_dir.path()),
+            &tarfile,
+            &PackOptions::default(),
+        )
+        .unwrap();
+        let url = serve_once(std::fs::read(&tarfile).unwrap());
+
+        let cached = tmp_dir.path().join("cache").join("src.tar.gz");
+        let stream_options = StreamOptions {
+            sha256: Some(sha256sum(&tarfile).unwrap()),
+            keep_copy: Some(cached.clone().into_os_string()),
+            ..StreamOptions::default()
+        };
+        let untar_directory = tmp_dir.path().join("untar");
+        let manifest = untar_url(
+            &url,
+            Some(&untar_directory),
+            &ExtractOptions::default(),
+            &stream_options,
+        )
+        .unwrap();
+
+        assert_eq!(manifest.entries.len(), 4);
+        assert!(untar_directory.join("sub").join("b.txt").exists());
+        assert_eq!(sha256sum(&cached).unwrap(), sha256sum(&tarfile).unwrap());
+
+        // Served once only, so this has to come from the kept copy
+        let again = tmp_dir.path().join("again");
+        untar_url(
+            &url,
+            Some(&again),
+            &ExtractOptions::default(),
+            &stream_options,
+        )
+        .unwrap();
+        assert!(again.join("a.txt").exists());
+    }
+
+    #[test]
+    fn test_untar_url_checksum_mismatch() {
+        let tmp_dir: TempDir = tempfile::Builder::new()
+            .prefix(env!("CARGO_PKG_NAME"))
+            .tempdir()
+            .unwrap();
+        let tarfile = tmp_dir.path().join("src.tar.gz");
+        pack(
+            pack_fixture(tmp_dir.path()),
+            &tarfile,
+            &PackOptions::default(),
+        )
+        .unwrap();
+        let url = serve_once(std::fs::read(&tarfile).unwrap());
+
+        let stream_options = StreamOptions {
+            sha256: Some(String::from("00")),
+            ..StreamOptions::default()
+        };
+        let untar_directory = tmp_dir.path().join("untar");
+        let error = untar_url(
+            &url,
+            Some(&untar_directory),
+            &ExtractOptions::default(),
+            &stream_options,
+        )
+        .unwrap_err();
+
+        assert!(matches!(error, Error::Checksum(_)));
+        assert_eq!(read_dir(&untar_directory).unwrap().count(), 0);
+
+        // Only what the archive created is removed
+        let url = serve_once(std::fs::read(&tarfile).unwrap());
+        let existing = untar_directory.join("sub").join("b.txt");
+        mkdirp(existing.parent().unwrap()).unwrap();
+        std::fs::write(&existing, "mine").unwrap();
+        let cached = tmp_dir.path().join("cache").join("src.tar.gz");
+        let stream_options = StreamOptions {
+            keep_copy: Some(cached.clone().into_os_string()),
+            ..stream_options
+        };
+        untar_url(
+            &url,
+            Some(&untar_directory),
+            &ExtractOptions::default(),
+            &stream_options,
+        )
+        .unwrap_err();
+        assert_eq!(std::fs::read(&existing).unwrap(), b"mine");
+        assert!(!untar_directory.join("a.txt").exists());
+        // Nothing but what was there before, and no backups
+        assert_eq!(read_dir(&untar_directory).unwrap().count(), 1);
+        assert_eq!(read_dir(tmp_dir.path().join("cache")).unwrap().count(), 0);
+    }
+
+    fn untar_limited(tarfile: &Path, dest: &Path, limits: ExtractLimits) -> ExtractLimitError {
+        let options = ExtractOptions {
+            limits,
+            ..ExtractOptions::default()
+        };
+        let error = untar_with_options(tarfile, Some(dest), &options).unwrap_err();
+        assert_eq!(read_dir(dest).unwrap().count(), 0);
+        match error {
+            Error::Limit(e) => e,
+            e => panic!("{}", e),
+        }
+    }
+
+    #[test]
+    fn test_untar_limits() {
+        let tmp_dir: TempDir = tempfile::Builder::new()
+            .prefix(env!("CARGO_PKG_NAME"))
+            .tempdir()
+            .unwrap();
+        let tarfile = tmp_dir.path().join("src.tar.gz");
+        pack(
+            pack_fixture(tmp_dir.path()),
+            &tarfile,
+            &PackOptions::default(),
+        )
+        .unwrap();
+        let untar_directory = tmp_dir.path().join("untar");
+
+        let limits = ExtractLimits {
+            max_entries: Some(2),
+            ..ExtractLimits::default()
+        };
+        match untar_limited(&tarfile, &untar_directory, limits) {
+            ExtractLimitError::Entries(2) => {}
+            e => panic!("{}", e),
+        }
+
+        let limits = ExtractLimits {
+            max_total_bytes: Some(2),
+            ..ExtractLimits::default()
+        };
+        match untar_limited(&tarfile, &untar_directory, limits) {
+            ExtractLimitError::TotalBytes(2) => {}
+            e => panic!("{}", e),
+        }
+
+        let limits = ExtractLimits {
+            max_file_bytes: Some(3),
+            ..ExtractLimits::default()
+        };
+        match untar_limited(&tarfile, &untar_directory, limits) {
+            ExtractLimitError::FileBytes(path, 3) => assert_eq!(path, Path::new("sub/skip.log")),
+            e => panic!("{}", e),
+        }
+
+        let limits = ExtractLimits {
+            max_path_len: Some(4),
+            ..ExtractLimits::default()
+        };
+        match untar_limited(&tarfile, &untar_directory, limits) {
+            ExtractLimitError::PathLength(path) => assert_eq!(path, Path::new("a.txt")),
+            e => panic!("{}", e),
+        }
+    }
+
+    #[test]
+    fn test_untar_ratio_limit() {
+        let tmp_dir: TempDir = tempfile::Builder::new()
+            .prefix(env!("CARGO_PKG_NAME"))
+            .tempdir()
+            .unwrap();
+        let src = tmp_dir.path().join("src");
+        mkdirp(&src).unwrap();
+        std::fs::write(src.join("zeros"), vec![0; 8 << 20]).unwrap();
+        let tarfile = tmp_dir.path().join("zeros.tar.gz");
+        pack(&src, &tarfile, &PackOptions::default()).unwrap();
+
+        let limits = ExtractLimits {
+            max_ratio: Some(100),
+            ..ExtractLimits::default()
+        };
+        match untar_limited(&tarfile, &tmp_dir.path().join("untar"), limits) {
+            ExtractLimitError::CompressionRatio(100) => {}
+            e => panic!("{}", e),
+        }
     }
 
     #[test]
@@ -113,7 +1489,7 @@ mod tests {
             .tempdir()
             .unwrap();
 
-        let tmp_dir = _tmp_dir.into_path().join("test_untar_all_in_dir");
+        let tmp_dir = _tmp_dir.path().join("test_untar_all_in_dir");
         mkdirp(&tmp_dir).unwrap();
 
         let tarfile = tmp_dir.join("example.tar.gz");
@@ -123,7 +1499,476 @@ mod tests {
         mkdirp(&untar_directory).unwrap();
         untar_all_in_dir(&tmp_dir, Some(&untar_directory)).unwrap();
 
-        assert_eq!(untar_directory.join(file!()).exists(), true);
+        assert!(untar_directory.join(file!()).exists());
         std::fs::remove_dir_all(tmp_dir).unwrap(); // TempDir should've cleaned this one :\
     }
+
+    #[test]
+    fn test_untar_all_in_dir_bare_compressed() {
+        let tmp_dir: TempDir = tempfile::Builder::new()
+            .prefix(env!("CARGO_PKG_NAME"))
+            .tempdir()
+            .unwrap();
+        let input = tmp_dir.path().join("in");
+        mkdirp(&input).unwrap();
+        tar(input.join("example.tar.gz"));
+        let mut enc = GzEncoder::new(
+            File::create(input.join("tool.gz")).unwrap(),
+            Compression::default(),
+        );
+        enc.write_all(b"#!/bin/sh\n").unwrap();
+        enc.finish().unwrap();
+
+        let untar_directory = tmp_dir.path().join("untar");
+        untar_all_in_dir(&input, Some(&untar_directory)).unwrap();
+
+        assert!(untar_directory.join(file!()).exists());
+        assert_eq!(
+            std::fs::read(untar_directory.join("tool")).unwrap(),
+            b"#!/bin/sh\n"
+        );
+    }
+
+    #[test]
+    fn test_untar_all_in_dir_recursive() {
+        let tmp_dir: TempDir = tempfile::Builder::new()
+            .prefix(env!("CARGO_PKG_NAME"))
+            .tempdir()
+            .unwrap();
+        let input = tmp_dir.path().join("in");
+        mkdirp(input.join("nested")).unwrap();
+        tar(input.join("a.tar.gz"));
+        tar(input.join("nested").join("b.tgz"));
+        std::fs::write(input.join("README"), "readme").unwrap();
+
+        let untar_directory = input.join("untar");
+        let options = UntarAllOptions {
+            recursive: true,
+            subdirs: true,
+            ..UntarAllOptions::default()
+        };
+        let report =
+            untar_all_in_dir_with_options(&input, Some(&untar_directory), &options).unwrap();
+
+        assert_eq!(report.extracted.len(), 2);
+        assert_eq!(report.skipped, vec![input.join("README")]);
+        assert!(report.failed.is_empty());
+        assert!(untar_directory.join("a").join(file!()).exists());
+        assert!(untar_directory
+            .join("nested")
+            .join("b")
+            .join(file!())
+            .exists());
+
+        // A second run doesn't descend into what the first extracted
+        let report =
+            untar_all_in_dir_with_options(&input, Some(&untar_directory), &options).unwrap();
+        assert_eq!(report.extracted.len(), 2);
+    }
+
+    #[test]
+    fn test_untar_all_in_dir_collision() {
+        let tmp_dir: TempDir = tempfile::Builder::new()
+            .prefix(env!("CARGO_PKG_NAME"))
+            .tempdir()
+            .unwrap();
+        let input = tmp_dir.path().join("in");
+        mkdirp(&input).unwrap();
+        tar(input.join("a.tar.gz"));
+        tar(input.join("b.tar.gz"));
+
+        let report = untar_all_in_dir(&input, Some(tmp_dir.path().join("untar"))).unwrap();
+
+        assert_eq!(report.extracted.len(), 1);
+        assert_eq!(report.failed.len(), 1);
+        let (archive, err) = &report.failed[0];
+        assert_eq!(archive, &input.join("b.tar.gz"));
+        let collision = match err {
+            Error::Collision(e) => e,
+            e => panic!("expected a collision, got {}", e),
+        };
+        assert_eq!(collision.path, PathBuf::from(file!()));
+        assert_eq!(collision.other, input.join("a.tar.gz"));
+
+        // `a.tar` fails on `x/f`, since `x` is a file, so `b.tar` may write `y`
+        let input = tmp_dir.path().join("failed");
+        let extract_dir = tmp_dir.path().join("failed-untar");
+        mkdirp(&input).unwrap();
+        mkdirp(&extract_dir).unwrap();
+        std::fs::write(extract_dir.join("x"), "x").unwrap();
+        for (name, paths) in [("a.tar", &["x/f", "y"][..]), ("b.tar", &["y"][..])] {
+            let mut builder = Builder::new(File::create(input.join(name)).unwrap());
+            for path in paths {
+                let mut header = tar::Header::new_gnu();
+                header.set_size(1);
+                header.set_mode(0o644);
+                header.set_cksum();
+                builder.append_data(&mut header, path, &b"1"[..]).unwrap();
+            }
+            builder.finish().unwrap();
+        }
+
+        let report = untar_all_in_dir(&input, Some(&extract_dir)).unwrap();
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].0, input.join("a.tar"));
+        match &report.failed[0].1 {
+            Error::Io { path, .. } => assert_eq!(path, &Some(extract_dir.join("x").join("f"))),
+            e => panic!("expected an io error, got {}", e),
+        }
+        assert_eq!(report.extracted.len(), 1);
+        assert_eq!(report.extracted[0].0, input.join("b.tar"));
+    }
+
+    #[test]
+    fn test_pack_reproducible() {
+        let tmp_dir: TempDir = tempfile::Builder::new()
+            .prefix(env!("CARGO_PKG_NAME"))
+            .tempdir()
+            .unwrap();
+        let src = pack_fixture(tmp_dir.path());
+        let options = PackOptions {
+            reproducible: true,
+            mtime: 1_500_000_000,
+            ..PackOptions::default()
+        };
+        let first = tmp_dir.path().join("first.tar.gz");
+        pack(&src, &first, &options).unwrap();
+
+        // Same contents, different mtimes and modes
+        let then = FileTime::from_unix_time(1_000_000, 0);
+        filetime::set_file_times(src.join("a.txt"), then, then).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let perms = std::fs::Permissions::from_mode(0o600);
+            std::fs::set_permissions(src.join("sub").join("b.txt"), perms).unwrap();
+        }
+        let second = tmp_dir.path().join("second.tar.gz");
+        pack(&src, &second, &options).unwrap();
+
+        assert_eq!(sha256sum(&first).unwrap(), sha256sum(&second).unwrap());
+
+        let file = File::open(&first).unwrap();
+        let mut archive = Archive::new(GzDecoder::new(BufReader::new(file)));
+        for entry in archive.entries().unwrap() {
+            let header = entry.unwrap().header().clone();
+            assert_eq!(header.mtime().unwrap(), 1_500_000_000);
+            assert_eq!((header.uid().unwrap(), header.gid().unwrap()), (0, 0));
+            assert!([0o644, 0o755].contains(&header.mode().unwrap()));
+            assert_eq!(header.username().unwrap(), Some(""));
+        }
+    }
+
+    #[test]
+    fn test_list_verify() {
+        let tmp_dir: TempDir = tempfile::Builder::new()
+            .prefix(env!("CARGO_PKG_NAME"))
+            .tempdir()
+            .unwrap();
+        let src = pack_fixture(tmp_dir.path());
+        let tarfile = tmp_dir.path().join("src.tar.gz");
+        pack(&src, &tarfile, &PackOptions::default()).unwrap();
+
+        let options = ExtractOptions {
+            digest: true,
+            ..ExtractOptions::default()
+        };
+        let listed = list_with_options(&tarfile, &options).unwrap();
+        let paths: Vec<&Path> = listed.iter().map(|e| e.path.as_path()).collect();
+        assert_eq!(
+            paths,
+            vec![
+                Path::new("a.txt"),
+                Path::new("sub"),
+                Path::new("sub/b.txt"),
+                Path::new("sub/skip.log")
+            ]
+        );
+        assert_eq!(listed[0].kind, EntryKind::File);
+        assert_eq!(listed[0].size, 1);
+        assert_eq!(
+            listed[0].digest,
+            Some(sha256sum(src.join("a.txt")).unwrap())
+        );
+        assert_eq!(listed[1].kind, EntryKind::Directory);
+
+        verify(&tarfile, &["./sub/b.txt", "a.txt"]).unwrap();
+        let err = verify(&tarfile, &["bin/postgres"]).unwrap_err();
+        let missing = match err {
+            Error::MissingEntries(e) => e,
+            e => panic!("expected missing entries, got {}", e),
+        };
+        assert_eq!(missing.missing, vec![PathBuf::from("bin/postgres")]);
+        assert!(!tmp_dir.path().join("a.txt").exists());
+    }
+
+    #[test]
+    fn test_untar_keep_existing() {
+        let tmp_dir: TempDir = tempfile::Builder::new()
+            .prefix(env!("CARGO_PKG_NAME"))
+            .tempdir()
+            .unwrap();
+        let src = pack_fixture(tmp_dir.path());
+        let tarfile = tmp_dir.path().join("src.tar.gz");
+        pack(&src, &tarfile, &PackOptions::default()).unwrap();
+        let dest = tmp_dir.path().join("dest");
+        untar(&tarfile, Some(&dest)).unwrap();
+        std::fs::write(dest.join("a.txt"), "changed").unwrap();
+        std::fs::remove_file(dest.join("sub").join("b.txt")).unwrap();
+
+        let options = ExtractOptions {
+            keep_existing: true,
+            ..ExtractOptions::default()
+        };
+        let manifest = untar_with_options(&tarfile, Some(&dest), &options).unwrap();
+        let paths: Vec<&Path> = manifest.entries.iter().map(|e| e.path.as_path()).collect();
+        assert_eq!(paths, vec![Path::new("sub/b.txt")]);
+        assert_eq!(std::fs::read(dest.join("a.txt")).unwrap(), b"changed");
+    }
+
+    #[test]
+    fn test_verify_corrupt() {
+        let tmp_dir: TempDir = tempfile::Builder::new()
+            .prefix(env!("CARGO_PKG_NAME"))
+            .tempdir()
+            .unwrap();
+        let src = pack_fixture(tmp_dir.path());
+        let no: [&str; 0] = [];
+
+        // gzip CRC, in the second to last 4 bytes
+        let tarfile = tmp_dir.path().join("src.tar.gz");
+        pack(&src, &tarfile, &PackOptions::default()).unwrap();
+        let mut bytes = std::fs::read(&tarfile).unwrap();
+        let crc = bytes.len() - 8;
+        bytes[crc] ^= 0xff;
+        std::fs::write(&tarfile, bytes).unwrap();
+        assert!(verify(&tarfile, &no).is_err());
+
+        // tar header, first byte of the first name
+        let tarfile = tmp_dir.path().join("src.tar");
+        let options = PackOptions {
+            format: ArchiveFormat::Tar,
+            ..PackOptions::default()
+        };
+        pack(&src, &tarfile, &options).unwrap();
+        let mut bytes = std::fs::read(&tarfile).unwrap();
+        bytes[0] ^= 0x01;
+        std::fs::write(&tarfile, bytes).unwrap();
+        assert!(verify(&tarfile, &no).is_err());
+    }
+
+    #[test]
+    fn test_decompress() {
+        let tmp_dir: TempDir = tempfile::Builder::new()
+            .prefix(env!("CARGO_PKG_NAME"))
+            .tempdir()
+            .unwrap();
+        let body = b"not a tarball".repeat(100);
+        let compressed: Vec<(&str, Vec<u8>)> = vec![
+            ("gz", {
+                let mut enc = GzEncoder::new(Vec::new(), Compression::default());
+                enc.write_all(&body).unwrap();
+                enc.finish().unwrap()
+            }),
+            ("xz", {
+                let mut enc = XzEncoder::new(Vec::new(), 6);
+                enc.write_all(&body).unwrap();
+                enc.finish().unwrap()
+            }),
+            ("bz2", {
+                let mut enc = BzEncoder::new(Vec::new(), bzip2::Compression::default());
+                enc.write_all(&body).unwrap();
+                enc.finish().unwrap()
+            }),
+            ("zst", zstd::encode_all(&body[..], 3).unwrap()),
+        ];
+
+        for (ext, bytes) in compressed {
+            let file = tmp_dir.path().join(format!("tool.{}", ext));
+            std::fs::write(&file, bytes).unwrap();
+            assert!(!is_tarball(&file).unwrap());
+
+            let dest = tmp_dir.path().join(ext).join("tool");
+            let manifest = decompress(&file, &dest).unwrap();
+            assert_eq!(std::fs::read(&dest).unwrap(), body);
+            assert_eq!(manifest.entries.len(), 1);
+            assert_eq!(manifest.entries[0].path, PathBuf::from("tool"));
+            assert_eq!(manifest.entries[0].size, body.len() as u64);
+        }
+    }
+
+    #[test]
+    fn test_decompress_limit() {
+        let tmp_dir: TempDir = tempfile::Builder::new()
+            .prefix(env!("CARGO_PKG_NAME"))
+            .tempdir()
+            .unwrap();
+        let file = tmp_dir.path().join("tool.zst");
+        std::fs::write(&file, zstd::encode_all(&[0u8; 4096][..], 3).unwrap()).unwrap();
+
+        let dest = tmp_dir.path().join("tool");
+        let options = ExtractOptions {
+            limits: ExtractLimits {
+                max_file_bytes: Some(1024),
+                ..ExtractLimits::default()
+            },
+            ..ExtractOptions::default()
+        };
+        let err = decompress_with_options(&file, &dest, &options).unwrap_err();
+        assert!(matches!(err, Error::Limit(_)));
+        assert!(!dest.exists());
+    }
+
+    #[test]
+    fn test_pack_formats() {
+        let tmp_dir: TempDir = tempfile::Builder::new()
+            .prefix(env!("CARGO_PKG_NAME"))
+            .tempdir()
+            .unwrap();
+        let src = pack_fixture(tmp_dir.path());
+
+        for (format, name) in [
+            (ArchiveFormat::Tar, "src.tar"),
+            (ArchiveFormat::TarXz, "src.tar.xz"),
+            (ArchiveFormat::TarBz2, "src.tar.bz2"),
+            (ArchiveFormat::TarZst, "src.tar.zst"),
+        ] {
+            let tarfile = tmp_dir.path().join("out").join(name);
+            let options = PackOptions {
+                format,
+                ..PackOptions::default()
+            };
+            pack(&src, &tarfile, &options).unwrap();
+            assert!(is_tarball(&tarfile).unwrap());
+
+            let untar_directory = tmp_dir.path().join(name);
+            untar(&tarfile, Some(&untar_directory)).unwrap();
+            assert!(untar_directory.join("sub").join("b.txt").exists());
+        }
+
+        for (format, level) in [
+            (ArchiveFormat::TarXz, 10),
+            (ArchiveFormat::TarBz2, 0),
+            (ArchiveFormat::TarZst, 23),
+        ] {
+            let tarfile = tmp_dir.path().join("out").join("out-of-range");
+            let options = PackOptions {
+                format,
+                compression_level: level,
+                ..PackOptions::default()
+            };
+            assert!(matches!(
+                pack(&src, &tarfile, &options),
+                Err(Error::Archive { .. })
+            ));
+            assert!(!tarfile.exists());
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_untar_metadata() {
+        use std::os::unix::fs::MetadataExt;
+
+        let tmp_dir: TempDir = tempfile::Builder::new()
+            .prefix(env!("CARGO_PKG_NAME"))
+            .tempdir()
+            .unwrap();
+        let tarfile = tmp_dir.path().join("meta.tar");
+        let mut builder = Builder::new(File::create(&tarfile).unwrap());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(4);
+        header.set_mode(0o4777);
+        header.set_uid(1234);
+        header.set_gid(1234);
+        header.set_mtime(1_000_000);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, "bin/tool", &b"tool"[..])
+            .unwrap();
+        builder.finish().unwrap();
+
+        let extract = |name: &str, options: &ExtractOptions| {
+            let dir = tmp_dir.path().join(name);
+            untar_with_options(&tarfile, Some(&dir), options).unwrap();
+            dir.join("bin").join("tool").metadata().unwrap()
+        };
+
+        let metadata = extract("default", &ExtractOptions::default());
+        assert_eq!(metadata.mode() & 0o7777, 0o777);
+        assert_eq!(metadata.mtime(), 1_000_000);
+
+        let me = tmp_dir.path().metadata().unwrap();
+        let options = ExtractOptions {
+            preserve_permissions: true,
+            umask: 0o022,
+            ownership: Ownership::Remap {
+                uids: vec![(1234, me.uid())].into_iter().collect(),
+                gids: vec![(1234, me.gid())].into_iter().collect(),
+            },
+            mtime: Mtime::Fixed(42),
+            ..ExtractOptions::default()
+        };
+        let metadata = extract("options", &options);
+        assert_eq!(metadata.mode() & 0o7777, 0o4755);
+        assert_eq!((metadata.uid(), metadata.gid()), (me.uid(), me.gid()));
+        assert_eq!(metadata.mtime(), 42);
+    }
+
+    fn pack_fixture(root: &Path) -> PathBuf {
+        let src = root.join("src");
+        mkdirp(src.join("sub")).unwrap();
+        std::fs::write(src.join("a.txt"), "a").unwrap();
+        std::fs::write(src.join("sub").join("b.txt"), "b").unwrap();
+        std::fs::write(src.join("sub").join("skip.log"), "skip").unwrap();
+        src
+    }
+
+    #[test]
+    fn test_pack() {
+        let tmp_dir: TempDir = tempfile::Builder::new()
+            .prefix(env!("CARGO_PKG_NAME"))
+            .tempdir()
+            .unwrap();
+        let src = pack_fixture(tmp_dir.path());
+
+        let tarfile = tmp_dir.path().join("out").join("src.tar.gz");
+        let options = PackOptions {
+            exclude: vec![String::from("*.log")],
+            ..PackOptions::default()
+        };
+        pack(&src, &tarfile, &options).unwrap();
+
+        let untar_directory = tmp_dir.path().join("untar");
+        untar(&tarfile, Some(&untar_directory)).unwrap();
+
+        assert!(untar_directory.join("a.txt").exists());
+        assert!(untar_directory.join("sub").join("b.txt").exists());
+        assert!(!untar_directory.join("sub").join("skip.log").exists());
+    }
+
+    #[test]
+    fn test_pack_deterministic() {
+        let tmp_dir: TempDir = tempfile::Builder::new()
+            .prefix(env!("CARGO_PKG_NAME"))
+            .tempdir()
+            .unwrap();
+        let src = pack_fixture(tmp_dir.path());
+
+        // Output inside the packed dir must not end up in its own archive
+        let first = src.join("first.tar");
+        let second = tmp_dir.path().join("second.tar");
+        let options = PackOptions {
+            format: ArchiveFormat::Tar,
+            ..PackOptions::default()
+        };
+        pack(&src, &first, &options).unwrap();
+        std::fs::rename(&first, tmp_dir.path().join("first.tar")).unwrap();
+        pack(&src, &second, &options).unwrap();
+
+        assert_eq!(
+            std::fs::read(tmp_dir.path().join("first.tar")).unwrap(),
+            std::fs::read(second).unwrap()
+        );
+    }
 }
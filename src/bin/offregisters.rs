@@ -0,0 +1,8 @@
+//! The command line on its own has no registers to offer: tools bundling
+//! `offregisters-*` crates call `liboffregisters::cli::main` with theirs.
+
+use liboffregisters::registry::Registry;
+
+fn main() {
+    std::process::exit(liboffregisters::cli::main(&Registry::default()))
+}
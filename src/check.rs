@@ -0,0 +1,196 @@
+use std::fmt;
+use std::io;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::Path;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::download::{stream, StreamOptions};
+use crate::error::{Error, IoContext};
+use crate::fs::sha256sum;
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CheckResult {
+    pub name: String,
+    /// Why the check failed, if it did
+    pub error: Option<String>,
+}
+
+impl CheckResult {
+    pub fn passed(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// What a register's `verify` found
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Report {
+    pub checks: Vec<CheckResult>,
+}
+
+impl Report {
+    /// Adds the outcome of one of the checks below, or any other
+    pub fn check<N: Into<String>>(&mut self, name: N, result: Result<(), Error>) -> &mut Self {
+        self.checks.push(CheckResult {
+            name: name.into(),
+            error: result.err().map(|e| e.to_string()),
+        });
+        self
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.checks.iter().all(CheckResult::passed)
+    }
+
+    pub fn failed(&self) -> impl Iterator<Item = &CheckResult> {
+        self.checks.iter().filter(|c| !c.passed())
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for check in &self.checks {
+            match &check.error {
+                None => writeln!(f, "ok   {}", check.name)?,
+                Some(error) => writeln!(f, "FAIL {}: {}", check.name, error)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `path` exists, and has at least the permission bits of `mode` set, so
+/// `0o111` checks that a binary is executable by everyone
+pub fn file_mode<P: AsRef<Path>>(path: P, mode: u32) -> Result<(), Error> {
+    let path = path.as_ref();
+    let metadata = path.metadata().at(path)?;
+    let actual = permission_bits(&metadata);
+    if actual & mode != mode {
+        return Err(Error::invalid_path(
+            path,
+            format!("mode is {:o}, expected at least {:o}", actual, mode),
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn permission_bits(metadata: &std::fs::Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+fn permission_bits(metadata: &std::fs::Metadata) -> u32 {
+    if metadata.permissions().readonly() {
+        0o555
+    } else {
+        0o777
+    }
+}
+
+/// The sha256 of the file at `path` is `expected` (hex, any case)
+pub fn digest<P: AsRef<Path>>(path: P, expected: &str) -> Result<(), Error> {
+    let path = path.as_ref();
+    let actual = sha256sum(path)?;
+    if !actual.eq_ignore_ascii_case(expected) {
+        return Err(Error::invalid_path(
+            path,
+            format!("sha256 is {}, expected {}", actual, expected),
+        ));
+    }
+    Ok(())
+}
+
+/// Something accepts TCP connections on `addr`, e.g. `"127.0.0.1:5432"`
+pub fn tcp_port<A: ToSocketAddrs + fmt::Display>(addr: A, timeout: Duration) -> Result<(), Error> {
+    let unreachable = |source: io::Error| {
+        Error::other(io::Error::new(
+            source.kind(),
+            format!("{}: {}", addr, source),
+        ))
+    };
+    let mut last = io::Error::new(io::ErrorKind::NotFound, "no addresses");
+    for socket in addr.to_socket_addrs().map_err(unreachable)? {
+        match TcpStream::connect_timeout(&socket, timeout) {
+            Ok(_) => return Ok(()),
+            Err(e) => last = e,
+        }
+    }
+    Err(unreachable(last))
+}
+
+/// A GET of `url` answers with a 2xx status
+pub fn http_ok(url: &Url, timeout: Duration) -> Result<(), Error> {
+    let options = StreamOptions {
+        timeout_ms: timeout.as_millis() as u64,
+        ..StreamOptions::default()
+    };
+    stream(url, &options).map(|_| ())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::TcpListener;
+
+    use tempfile::TempDir;
+
+    use crate::download::serve_once;
+
+    #[test]
+    fn test_checks() {
+        let tmp_dir: TempDir = tempfile::Builder::new()
+            .prefix(env!("CARGO_PKG_NAME"))
+            .tempdir()
+            .unwrap();
+        let file = tmp_dir.path().join("tool");
+        std::fs::write(&file, "#!/bin/sh\n").unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let closed = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+
+        let mut report = Report::default();
+        report
+            .check("exists", file_mode(&file, 0o400))
+            .check("missing", file_mode(tmp_dir.path().join("nope"), 0))
+            .check("digest", digest(&file, &sha256sum(&file).unwrap()))
+            .check("changed", digest(&file, "00"))
+            .check("port", tcp_port(addr, Duration::from_secs(1)))
+            .check("closed", tcp_port(closed, Duration::from_secs(1)))
+            .check(
+                "http",
+                http_ok(&serve_once(b"ok".to_vec()), Duration::from_secs(5)),
+            );
+
+        let failed: Vec<&str> = report.failed().map(|c| c.name.as_str()).collect();
+        assert_eq!(failed, vec!["missing", "changed", "closed"]);
+        assert!(!report.is_healthy());
+        assert!(report
+            .to_string()
+            .starts_with("ok   exists\nFAIL missing: "));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_file_mode() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let tmp_dir: TempDir = tempfile::Builder::new()
+            .prefix(env!("CARGO_PKG_NAME"))
+            .tempdir()
+            .unwrap();
+        let file = tmp_dir.path().join("tool");
+        std::fs::write(&file, "").unwrap();
+        std::fs::set_permissions(&file, std::fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(file_mode(&file, 0o111).is_err());
+        std::fs::set_permissions(&file, std::fs::Permissions::from_mode(0o755)).unwrap();
+        file_mode(&file, 0o111).unwrap();
+    }
+}
@@ -0,0 +1,447 @@
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use serde::Serialize;
+
+use crate::check::Report;
+use crate::config::ConfigSources;
+use crate::context::{Context, StderrLog};
+use crate::error::Error;
+use crate::graph::Orchestrator;
+use crate::registry::Registry;
+use crate::runner::Outcome;
+use crate::OffRegisters;
+
+const USAGE: &str = "\
+Usage: offregisters [options] <command> [register...]
+
+Commands:
+  list                  Show the available registers
+  status [register...]  Show whether registers are installed, and at which version
+  install <register...> Install registers, and what they depend on
+  uninstall <register...>
+  verify [register...]  Run the health checks of installed registers
+  repair [register...]  Put back what installed registers are missing
+  plan <register...>    Show what install would do, without doing it
+
+Options:
+  --prefix <dir>     Where registers should install to
+  --root <dir>       Install under <dir> instead of /, like DESTDIR
+  --cache-dir <dir>  Where downloads are kept
+  --state-dir <dir>  Where install receipts are kept
+  --config <file>    Settings for registers, in TOML or JSON
+  --set <register.key=value>
+                     Override a setting, over --config and the environment
+  --offline          Only use downloads that are already cached
+  --parallel         Run independent registers concurrently
+  --json             Print results as JSON
+  -v, --verbose      Log progress to stderr
+  -h, --help";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Command {
+    List,
+    Status,
+    Install,
+    Uninstall,
+    Verify,
+    Repair,
+    Plan,
+    Help,
+}
+
+#[derive(Debug, Default)]
+struct Args {
+    command: Option<Command>,
+    registers: Vec<String>,
+    prefix: Option<PathBuf>,
+    root: Option<PathBuf>,
+    cache_dir: Option<PathBuf>,
+    state_dir: Option<PathBuf>,
+    config: Option<PathBuf>,
+    overrides: Vec<String>,
+    offline: bool,
+    parallel: bool,
+    json: bool,
+    verbose: bool,
+}
+
+fn parse<I: IntoIterator<Item = String>>(args: I) -> Result<Args, String> {
+    let mut parsed = Args::default();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        let (flag, inline) = match arg.split_once('=') {
+            Some((flag, value)) if arg.starts_with("--") => (flag.to_string(), Some(value.into())),
+            _ => (arg.clone(), None),
+        };
+        let mut value = |name: &str| match inline.clone().or_else(|| args.next()) {
+            Some(value) => Ok(value),
+            None => Err(format!("{} needs a value", name)),
+        };
+        match flag.as_str() {
+            "--prefix" => parsed.prefix = Some(value("--prefix")?.into()),
+            "--root" => parsed.root = Some(value("--root")?.into()),
+            "--cache-dir" => parsed.cache_dir = Some(value("--cache-dir")?.into()),
+            "--state-dir" => parsed.state_dir = Some(value("--state-dir")?.into()),
+            "--config" => parsed.config = Some(value("--config")?.into()),
+            "--set" => parsed.overrides.push(value("--set")?),
+            "--offline" => parsed.offline = true,
+            "--parallel" => parsed.parallel = true,
+            "--json" => parsed.json = true,
+            "-v" | "--verbose" => parsed.verbose = true,
+            "-h" | "--help" => parsed.command = Some(Command::Help),
+            flag if flag.starts_with('-') => return Err(format!("unknown option {}", flag)),
+            _ if parsed.command.is_some() => parsed.registers.push(arg),
+            command => {
+                parsed.command = Some(match command {
+                    "list" => Command::List,
+                    "status" => Command::Status,
+                    "install" => Command::Install,
+                    "uninstall" => Command::Uninstall,
+                    "verify" => Command::Verify,
+                    "repair" => Command::Repair,
+                    "plan" => Command::Plan,
+                    "help" => Command::Help,
+                    _ => return Err(format!("unknown command {}", command)),
+                })
+            }
+        }
+    }
+    match parsed.command {
+        Some(Command::Install) | Some(Command::Uninstall) | Some(Command::Plan)
+            if parsed.registers.is_empty() =>
+        {
+            Err(String::from("no registers given"))
+        }
+        None => Err(String::from("no command given")),
+        _ => Ok(parsed),
+    }
+}
+
+impl Args {
+    fn context(&self) -> Result<Context, Error> {
+        let mut ctx = Context {
+            prefix: self.prefix.clone(),
+            root: self.root.clone(),
+            state_dir: self.state_dir.clone(),
+            offline: self.offline,
+            ..Context::default()
+        };
+        if let Some(cache_dir) = &self.cache_dir {
+            ctx.cache_dir = cache_dir.clone();
+        }
+        if self.verbose {
+            ctx.logger = Arc::new(StderrLog);
+        }
+        let mut config = ConfigSources::default();
+        if let Some(file) = &self.config {
+            config = config.with_file(file)?;
+        }
+        for spec in &self.overrides {
+            config = config.with_override(spec)?;
+        }
+        ctx.config = Arc::new(config);
+        Ok(ctx)
+    }
+
+    /// The named registers, or all of them
+    fn select<'a>(&self, registry: &'a Registry) -> Result<Vec<&'a dyn OffRegisters>, Error> {
+        if self.registers.is_empty() {
+            Ok(registry.iter().map(|(_, r)| r).collect())
+        } else {
+            registry.select(&self.registers)
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Row {
+    register: String,
+    ok: bool,
+    outcome: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    installed_version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    version: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    checks: Option<Report>,
+}
+
+impl Row {
+    fn from_outcome(register: String, outcome: Outcome) -> Self {
+        Row {
+            register,
+            ok: outcome.is_ok(),
+            outcome: outcome.to_string(),
+            installed_version: None,
+            version: None,
+            checks: match outcome {
+                Outcome::Verified(report) | Outcome::Repaired(report) => Some(report),
+                _ => None,
+            },
+        }
+    }
+
+    fn status(register: &dyn OffRegisters, ctx: &Context) -> Self {
+        let installed = register.already_setup(ctx).and_then(|setup| {
+            if setup {
+                register.installed_version(ctx).map(|v| (true, v))
+            } else {
+                Ok((false, None))
+            }
+        });
+        let (ok, outcome, installed_version) = match installed {
+            Ok((true, version)) => (true, String::from("installed"), version),
+            Ok((false, _)) => (true, String::from("not installed"), None),
+            Err(e) => (false, e.to_string(), None),
+        };
+        Row {
+            register: register.name().to_string(),
+            ok,
+            outcome,
+            installed_version,
+            version: register.version().map(String::from),
+            checks: None,
+        }
+    }
+}
+
+/// Runs the command line `args` (without the program name) against the
+/// registers of `registry`, writing results to `out`. Returns the exit
+/// status: 0 if everything succeeded, 1 if something failed, 2 for usage
+/// errors.
+pub fn run<I, W>(registry: &Registry, args: I, out: &mut W) -> Result<i32, Error>
+where
+    I: IntoIterator<Item = String>,
+    W: Write,
+{
+    let args = match parse(args) {
+        Ok(args) => args,
+        Err(message) => {
+            writeln!(out, "{}\n\n{}", message, USAGE)?;
+            return Ok(2);
+        }
+    };
+    match execute(registry, &args, out) {
+        Err(e) if args.json => {
+            let error = serde_json::json!({ "error": e.to_string() });
+            writeln!(out, "{}", serde_json::to_string_pretty(&error)?)?;
+            Ok(1)
+        }
+        Err(e) => {
+            writeln!(out, "error: {}", e)?;
+            Ok(1)
+        }
+        result => result,
+    }
+}
+
+fn execute<W: Write>(registry: &Registry, args: &Args, out: &mut W) -> Result<i32, Error> {
+    let ctx = args.context()?;
+    let orchestrator = Orchestrator {
+        parallel: args.parallel,
+        ..Orchestrator::default()
+    };
+
+    let rows: Vec<Row> = match args.command {
+        Some(Command::Help) | None => {
+            writeln!(out, "{}", USAGE)?;
+            return Ok(0);
+        }
+        Some(Command::List) => {
+            let metadata: Vec<_> = registry.iter().map(|(m, _)| m).collect();
+            if args.json {
+                writeln!(out, "{}", serde_json::to_string_pretty(&metadata)?)?;
+            } else {
+                for m in metadata {
+                    writeln!(out, "{}\t{}", m.name, m.description)?;
+                }
+            }
+            return Ok(0);
+        }
+        Some(Command::Plan) => {
+            let plan = orchestrator.plan(&args.select(registry)?, &ctx)?;
+            if args.json {
+                writeln!(out, "{}", plan.to_json()?)?;
+            } else {
+                write!(out, "{}", plan)?;
+            }
+            return Ok(0);
+        }
+        Some(Command::Status) => args
+            .select(registry)?
+            .into_iter()
+            .map(|register| Row::status(register, &ctx))
+            .collect(),
+        Some(Command::Install) => orchestrator
+            .install(&args.select(registry)?, &ctx)?
+            .into_iter()
+            .map(|(name, outcome)| Row::from_outcome(name, outcome))
+            .collect(),
+        Some(Command::Uninstall) => orchestrator
+            .uninstall(&args.select(registry)?, &ctx)?
+            .into_iter()
+            .map(|(name, outcome)| Row::from_outcome(name, outcome))
+            .collect(),
+        Some(Command::Verify) => orchestrator
+            .verify(&args.select(registry)?, &ctx)?
+            .into_iter()
+            .map(|(name, outcome)| Row::from_outcome(name, outcome))
+            .collect(),
+        Some(Command::Repair) => orchestrator
+            .repair(&args.select(registry)?, &ctx)?
+            .into_iter()
+            .map(|(name, outcome)| Row::from_outcome(name, outcome))
+            .collect(),
+    };
+
+    if args.json {
+        writeln!(out, "{}", serde_json::to_string_pretty(&rows)?)?;
+    } else {
+        for row in &rows {
+            write!(out, "{}: {}", row.register, row.outcome)?;
+            match (&row.installed_version, &row.version) {
+                (Some(installed), Some(version)) if installed != version => {
+                    write!(out, " at {}, {} available", installed, version)?
+                }
+                (Some(installed), _) => write!(out, " at {}", installed)?,
+                _ => {}
+            }
+            writeln!(out)?;
+            if let Some(report) = row.checks.as_ref().filter(|r| !r.is_healthy()) {
+                for check in report.failed() {
+                    writeln!(
+                        out,
+                        "  {}: {}",
+                        check.name,
+                        check.error.as_deref().unwrap_or_default()
+                    )?;
+                }
+            }
+        }
+    }
+    Ok(if rows.iter().all(|row| row.ok) { 0 } else { 1 })
+}
+
+/// `run` with the arguments and stdout of this process, for a host
+/// application's `main` to hand its registry to
+pub fn main(registry: &Registry) -> i32 {
+    let stdout = std::io::stdout();
+    match run(registry, std::env::args().skip(1), &mut stdout.lock()) {
+        Ok(status) => status,
+        Err(e) => {
+            eprintln!("offregisters: {}", e);
+            1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::path::Path;
+
+    use tempfile::TempDir;
+
+    struct Marker {
+        path: PathBuf,
+    }
+
+    impl OffRegisters for Marker {
+        fn name(&self) -> &str {
+            "marker"
+        }
+        fn version(&self) -> Option<&str> {
+            Some("2.0")
+        }
+        fn already_setup(&self, _ctx: &Context) -> Result<bool, Error> {
+            Ok(self.path.exists())
+        }
+        fn installed_version(&self, _ctx: &Context) -> Result<Option<String>, Error> {
+            Ok(Some(String::from("2.0")))
+        }
+        fn install(&self, ctx: &Context) -> Result<(), Error> {
+            ctx.write(&self.path, "")
+        }
+        fn uninstall(&self, _ctx: &Context) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    fn run_args(registry: &Registry, args: &[&str]) -> (i32, String) {
+        let mut out = Vec::new();
+        let args = args.iter().map(|a| a.to_string());
+        let status = run(registry, args, &mut out).unwrap();
+        (status, String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn test_cli() {
+        let tmp_dir: TempDir = tempfile::Builder::new()
+            .prefix(env!("CARGO_PKG_NAME"))
+            .tempdir()
+            .unwrap();
+        let marker = tmp_dir.path().join("prefix").join("marker");
+        let mut registry = Registry::default();
+        registry
+            .add(Marker {
+                path: marker.clone(),
+            })
+            .unwrap()
+            .description("Touches a file");
+        let state_dir = tmp_dir.path().join("state");
+        let state = format!("--state-dir={}", state_dir.display());
+
+        assert_eq!(
+            run_args(&registry, &["list"]),
+            (0, String::from("marker\tTouches a file\n"))
+        );
+        assert_eq!(
+            run_args(&registry, &["status"]),
+            (0, String::from("marker: not installed\n"))
+        );
+        let (status, out) = run_args(&registry, &["plan", "marker"]);
+        assert_eq!(status, 0);
+        assert!(out.starts_with("marker:\n  create directory "));
+        assert!(!marker.exists());
+
+        assert_eq!(
+            run_args(&registry, &["install", &state, "marker"]),
+            (0, String::from("marker: installed\n"))
+        );
+        assert!(marker.exists());
+        let (status, out) = run_args(&registry, &["--json", "status"]);
+        assert_eq!(status, 0);
+        let rows: serde_json::Value = serde_json::from_str(&out).unwrap();
+        assert_eq!(rows[0]["outcome"], "installed");
+        assert_eq!(rows[0]["installed_version"], "2.0");
+
+        std::fs::remove_file(&marker).unwrap();
+        assert_eq!(
+            run_args(&registry, &["repair", &state]),
+            (0, String::from("marker: repaired\n"))
+        );
+        assert!(marker.exists());
+
+        assert_eq!(
+            run_args(&registry, &["uninstall", &state, "marker"]),
+            (0, String::from("marker: uninstalled\n"))
+        );
+        assert!(!Path::new(&marker).exists());
+
+        let stage = tmp_dir.path().join("stage");
+        let root = format!("--root={}", stage.display());
+        assert_eq!(run_args(&registry, &["install", &root, "marker"]).0, 0);
+        assert!(crate::fs::rooted(&stage, &marker).exists());
+        assert!(!marker.exists());
+
+        assert_eq!(run_args(&registry, &["install", "postgres"]).0, 1);
+        assert_eq!(run_args(&registry, &["install"]).0, 2);
+        assert_eq!(run_args(&registry, &["--bogus", "list"]).0, 2);
+        let (status, out) = run_args(&registry, &["--set", "port=1", "list"]);
+        assert_eq!(status, 1);
+        assert!(out.starts_with("error: override \"port=1\": "));
+    }
+}
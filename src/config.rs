@@ -0,0 +1,362 @@
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fmt;
+use std::path::PathBuf;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use crate::error::{Error, IoContext};
+
+/// A register's settings. `Default` gives every setting a value, so that
+/// sources only need to mention what they change.
+pub trait Config: Serialize + DeserializeOwned + Default {
+    fn validate(&self) -> Result<(), Invalid> {
+        Ok(())
+    }
+}
+
+/// A setting that `Config::validate` rejected
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Invalid {
+    pub key: String,
+    pub reason: String,
+}
+
+/// Where a setting came from, for error messages
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Origin {
+    Default,
+    File(PathBuf),
+    Env(String),
+    Override(String),
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Origin::Default => write!(f, "the default"),
+            Origin::File(path) => write!(f, "{}", path.display()),
+            Origin::Env(key) => write!(f, "${}", key),
+            Origin::Override(spec) => write!(f, "override {:?}", spec),
+        }
+    }
+}
+
+/// Settings for every register, from lowest to highest precedence: the
+/// `Config` defaults, a TOML or JSON file with a table per register,
+/// `OFFREGISTERS_<NAME>_<KEY>` environment variables, and overrides such
+/// as `postgres.port=5433` from a command line.
+#[derive(Clone, Debug, Default)]
+pub struct ConfigSources {
+    file: Option<(PathBuf, Map<String, Value>)>,
+    overrides: Vec<(String, String, String)>,
+}
+
+impl ConfigSources {
+    /// Reads `path`, as JSON if it ends in `.json` and as TOML otherwise
+    pub fn with_file<P: Into<OsString>>(mut self, path: P) -> Result<Self, Error> {
+        let path = PathBuf::from(path.into());
+        let text = std::fs::read_to_string(&path).at(&path)?;
+        let malformed = |reason: String| Error::Config {
+            register: String::new(),
+            key: None,
+            origin: Origin::File(path.clone()),
+            reason,
+        };
+        let value: Value = if path.extension().is_some_and(|ext| ext == "json") {
+            serde_json::from_str(&text).map_err(|e| malformed(e.to_string()))?
+        } else {
+            let table: toml::Value = toml::from_str(&text).map_err(|e| malformed(e.to_string()))?;
+            serde_json::to_value(table)?
+        };
+        match value {
+            Value::Object(registers) => self.file = Some((path, registers)),
+            _ => {
+                return Err(Error::Config {
+                    register: String::new(),
+                    key: None,
+                    origin: Origin::File(path),
+                    reason: String::from("expected a table per register"),
+                })
+            }
+        }
+        Ok(self)
+    }
+
+    /// Adds a `register.key=value` override
+    pub fn with_override(mut self, spec: &str) -> Result<Self, Error> {
+        let parsed = spec
+            .split_once('=')
+            .and_then(|(path, value)| Some((path.split_once('.')?, value)));
+        match parsed {
+            Some(((register, key), value)) => {
+                self.overrides
+                    .push((register.to_string(), key.to_string(), value.to_string()));
+                Ok(self)
+            }
+            None => Err(Error::Config {
+                register: String::new(),
+                key: None,
+                origin: Origin::Override(spec.to_string()),
+                reason: String::from("expected register.key=value"),
+            }),
+        }
+    }
+
+    /// The settings of `register`, merged from every source and validated
+    pub fn load<T: Config>(&self, register: &str) -> Result<T, Error> {
+        let defaults = match serde_json::to_value(T::default())? {
+            Value::Object(defaults) => defaults,
+            _ => Map::new(),
+        };
+        let mut origins: BTreeMap<String, (Origin, Value)> = BTreeMap::new();
+
+        if let Some((path, registers)) = &self.file {
+            if let Some(Value::Object(table)) = registers.get(register) {
+                for (key, value) in table {
+                    origins.insert(key.clone(), (Origin::File(path.clone()), value.clone()));
+                }
+            }
+        }
+        for key in defaults.keys() {
+            let var = env_var(register, key);
+            if let Ok(raw) = std::env::var(&var) {
+                let value = parse_for::<T>(&raw, key, &defaults);
+                origins.insert(key.clone(), (Origin::Env(var), value));
+            }
+        }
+        for (name, key, raw) in &self.overrides {
+            if name == register {
+                let spec = format!("{}.{}={}", name, key, raw);
+                let value = parse_for::<T>(raw, key, &defaults);
+                origins.insert(key.clone(), (Origin::Override(spec), value));
+            }
+        }
+
+        let mut merged = defaults.clone();
+        for (key, (_, value)) in &origins {
+            merged.insert(key.clone(), value.clone());
+        }
+        let config: T = match serde_json::from_value(Value::Object(merged)) {
+            Ok(config) => config,
+            Err(e) => return Err(blame::<T>(register, &defaults, &origins, e)),
+        };
+        config.validate().map_err(|invalid| Error::Config {
+            register: register.to_string(),
+            origin: origins
+                .get(&invalid.key)
+                .map(|(origin, _)| origin.clone())
+                .unwrap_or(Origin::Default),
+            key: Some(invalid.key),
+            reason: invalid.reason,
+        })?;
+        Ok(config)
+    }
+}
+
+/// `OFFREGISTERS_POSTGRES_DATA_DIR` for `postgres` and `data_dir`
+pub fn env_var(register: &str, key: &str) -> String {
+    let upper = |s: &str| -> String {
+        s.chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() {
+                    c.to_ascii_uppercase()
+                } else {
+                    '_'
+                }
+            })
+            .collect()
+    };
+    format!("OFFREGISTERS_{}_{}", upper(register), upper(key))
+}
+
+/// Strings from the environment or a command line, typed like the default
+fn parse_like(raw: &str, like: &Value) -> Value {
+    match like {
+        Value::String(_) => Value::String(raw.to_string()),
+        _ => serde_json::from_str(raw).unwrap_or_else(|_| Value::String(raw.to_string())),
+    }
+}
+
+/// `parse_like`, but a `null` default says nothing about the type, so the
+/// parsed value is only kept if `T` accepts it, e.g. `123` for an
+/// `Option<String>` stays the string `"123"`
+fn parse_for<T: Config>(raw: &str, key: &str, defaults: &Map<String, Value>) -> Value {
+    let like = defaults.get(key).unwrap_or(&Value::Null);
+    let parsed = parse_like(raw, like);
+    if like.is_null() && !parsed.is_string() {
+        let mut single = defaults.clone();
+        single.insert(key.to_string(), parsed.clone());
+        if serde_json::from_value::<T>(Value::Object(single)).is_err() {
+            return Value::String(raw.to_string());
+        }
+    }
+    parsed
+}
+
+/// Finds the setting that makes `T` fail to deserialize, by trying each on
+/// its own against the defaults
+fn blame<T: Config>(
+    register: &str,
+    defaults: &Map<String, Value>,
+    origins: &BTreeMap<String, (Origin, Value)>,
+    error: serde_json::Error,
+) -> Error {
+    for (key, (origin, value)) in origins {
+        let mut single = defaults.clone();
+        single.insert(key.clone(), value.clone());
+        if let Err(e) = serde_json::from_value::<T>(Value::Object(single)) {
+            return Error::Config {
+                register: register.to_string(),
+                key: Some(key.clone()),
+                origin: origin.clone(),
+                reason: e.to_string(),
+            };
+        }
+    }
+    Error::Config {
+        register: register.to_string(),
+        key: None,
+        origin: Origin::Default,
+        reason: error.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use serde::Deserialize;
+    use tempfile::TempDir;
+
+    #[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+    #[serde(default)]
+    struct Postgres {
+        port: u16,
+        data_dir: String,
+        superuser: Option<String>,
+    }
+
+    impl Config for Postgres {
+        fn validate(&self) -> Result<(), Invalid> {
+            if self.port == 0 {
+                return Err(Invalid {
+                    key: String::from("port"),
+                    reason: String::from("must not be 0"),
+                });
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_config_sources() {
+        let tmp_dir: TempDir = tempfile::Builder::new()
+            .prefix(env!("CARGO_PKG_NAME"))
+            .tempdir()
+            .unwrap();
+        let file = tmp_dir.path().join("offregisters.toml");
+        std::fs::write(
+            &file,
+            "[postgres]\nport = 5432\ndata_dir = \"/var/lib/pg\"\n\n[other]\nport = 0\n",
+        )
+        .unwrap();
+        let sources = ConfigSources::default().with_file(&file).unwrap();
+        assert_eq!(
+            sources.load::<Postgres>("postgres").unwrap(),
+            Postgres {
+                port: 5432,
+                data_dir: String::from("/var/lib/pg"),
+                superuser: None,
+            }
+        );
+
+        std::env::set_var("OFFREGISTERS_CONFIG_TEST_DATA_DIR", "/srv/pg");
+        let sources = sources.with_override("config-test.port=6543").unwrap();
+        let config: Postgres = sources.load("config-test").unwrap();
+        assert_eq!(config.port, 6543);
+        assert_eq!(config.data_dir, "/srv/pg");
+
+        let sources = sources
+            .with_override("config-test.superuser=admin")
+            .unwrap();
+        assert_eq!(
+            sources.load::<Postgres>("config-test").unwrap().superuser,
+            Some(String::from("admin"))
+        );
+
+        match sources.load::<Postgres>("other") {
+            Err(Error::Config { key, origin, .. }) => {
+                assert_eq!(key.as_deref(), Some("port"));
+                assert_eq!(origin, Origin::File(file.clone()));
+            }
+            r => panic!("expected a config error, got {:?}", r),
+        }
+        std::env::remove_var("OFFREGISTERS_CONFIG_TEST_DATA_DIR");
+    }
+
+    #[test]
+    fn test_config_errors() {
+        std::env::set_var("OFFREGISTERS_CONFIG_ERRORS_PORT", "lots");
+        let err = ConfigSources::default()
+            .load::<Postgres>("config-errors")
+            .unwrap_err();
+        std::env::remove_var("OFFREGISTERS_CONFIG_ERRORS_PORT");
+        match &err {
+            Error::Config { key, origin, .. } => {
+                assert_eq!(key.as_deref(), Some("port"));
+                assert_eq!(
+                    origin,
+                    &Origin::Env(String::from("OFFREGISTERS_CONFIG_ERRORS_PORT"))
+                );
+            }
+            e => panic!("expected a config error, got {}", e),
+        }
+        assert!(err
+            .to_string()
+            .starts_with("config-errors.port from $OFFREGISTERS_CONFIG_ERRORS_PORT: "));
+
+        // The default port is 0, which doesn't validate
+        assert!(matches!(
+            ConfigSources::default().load::<Postgres>("config-errors"),
+            Err(Error::Config {
+                origin: Origin::Default,
+                ..
+            })
+        ));
+        assert!(ConfigSources::default().with_override("port=1").is_err());
+
+        let tmp_dir: TempDir = tempfile::Builder::new()
+            .prefix(env!("CARGO_PKG_NAME"))
+            .tempdir()
+            .unwrap();
+        let file = tmp_dir.path().join("offregisters.json");
+        std::fs::write(&file, "{\"postgres\": {\"port\": }").unwrap();
+        match ConfigSources::default().with_file(&file) {
+            Err(Error::Config { key, origin, .. }) => {
+                assert_eq!(key, None);
+                assert_eq!(origin, Origin::File(file));
+            }
+            r => panic!("expected a config error, got {:?}", r.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn test_config_untyped() {
+        // `superuser` defaults to null, so a number is kept as a string
+        let sources = ConfigSources::default()
+            .with_override("config-untyped.port=1")
+            .unwrap()
+            .with_override("config-untyped.superuser=123")
+            .unwrap();
+        assert_eq!(
+            sources
+                .load::<Postgres>("config-untyped")
+                .unwrap()
+                .superuser,
+            Some(String::from("123"))
+        );
+    }
+}
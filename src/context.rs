@@ -0,0 +1,552 @@
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use sha2::{Digest, Sha256};
+use url::Url;
+
+use crate::archive::{untar_url, untar_with_options, ExtractOptions};
+use crate::config::{Config, ConfigSources};
+use crate::download::{stream, StreamOptions};
+use crate::error::{Error, IoContext};
+use crate::event::{Event, Observers};
+use crate::fs::{mkdirp, rooted};
+use crate::manifest::Manifest;
+use crate::plan::{Action, Recorder};
+use crate::receipt::{ReceiptStore, Record, Recording, Source};
+
+/// Held while the environment is read or changed through a `Context`, or
+/// put back by `Receipt::replay`, since registers may run in parallel
+static ENV_LOCK: Mutex<()> = Mutex::new(());
+
+pub(crate) fn lock_env() -> MutexGuard<'static, ()> {
+    ENV_LOCK
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Somewhere for registers to report progress
+pub trait Log: Send + Sync {
+    fn log(&self, message: &str);
+}
+
+/// Discards everything
+pub struct NullLog;
+
+impl Log for NullLog {
+    fn log(&self, _message: &str) {}
+}
+
+pub struct StderrLog;
+
+impl Log for StderrLog {
+    fn log(&self, message: &str) {
+        eprintln!("{}", message);
+    }
+}
+
+/// Shared settings and services handed to every `OffRegisters` call.
+/// Registers that download, extract and write through its helpers get dry
+/// runs for free.
+#[derive(Clone)]
+pub struct Context {
+    /// Downloads are kept here, and reused when they are still there
+    pub cache_dir: PathBuf,
+    /// Where registers that can be told where to install should do so
+    pub prefix: Option<PathBuf>,
+    /// Install under this directory instead of `/`, like `DESTDIR`: the
+    /// `Context` helpers put absolute target paths under it. The functions
+    /// of `fs`, `archive` and `download` take paths as given, so registers
+    /// calling them directly should `resolve` their targets first.
+    pub root: Option<PathBuf>,
+    /// Only use downloads that are already in `cache_dir`
+    pub offline: bool,
+    pub extract: ExtractOptions,
+    pub logger: Arc<dyn Log>,
+    /// Told of what the helpers download, extract and write, on top of
+    /// the observers of `extract`
+    pub observers: Observers,
+    /// Set for a dry run: helpers record what they would do here instead.
+    /// The free functions in `download`, `archive` and `fs` ignore it.
+    pub plan: Option<Arc<Recorder>>,
+    /// Where receipts of installed registers are kept, if anywhere
+    pub state_dir: Option<PathBuf>,
+    /// Only put back what is missing: the helpers leave files that are
+    /// already there alone, see `Runner::repair`
+    pub repair: bool,
+    /// Set while a register installs: helpers record what they did here.
+    /// The free functions in `download`, `archive` and `fs` record nothing.
+    pub receipt: Option<Arc<Recording>>,
+    /// Where registers' settings come from, see `config`
+    pub config: Arc<ConfigSources>,
+}
+
+impl Default for Context {
+    fn default() -> Self {
+        Context {
+            cache_dir: std::env::temp_dir().join("offregisters"),
+            prefix: None,
+            root: None,
+            offline: false,
+            extract: ExtractOptions::default(),
+            logger: Arc::new(NullLog),
+            observers: Observers::default(),
+            plan: None,
+            state_dir: None,
+            repair: false,
+            receipt: None,
+            config: Arc::default(),
+        }
+    }
+}
+
+impl Context {
+    pub fn log(&self, message: &str) {
+        self.logger.log(message)
+    }
+
+    /// The settings of `register`, from `config`
+    pub fn config<T: Config>(&self, register: &str) -> Result<T, Error> {
+        self.config.load(register)
+    }
+
+    pub fn is_dry_run(&self) -> bool {
+        self.plan.is_some()
+    }
+
+    /// True if this is a dry run, after recording `action`
+    fn planned(&self, action: Action) -> bool {
+        match &self.plan {
+            Some(plan) => {
+                plan.record(action);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Where `path` really is, given `root`
+    pub fn resolve<P: AsRef<Path>>(&self, path: P) -> PathBuf {
+        match &self.root {
+            Some(root) => rooted(root, path),
+            None => path.as_ref().to_path_buf(),
+        }
+    }
+
+    pub fn receipts(&self) -> Option<ReceiptStore> {
+        self.state_dir.as_ref().map(ReceiptStore::new)
+    }
+
+    /// Adds to the receipt of the register being installed, for things
+    /// done without these helpers
+    pub fn record(&self, record: Record) {
+        if let Some(receipt) = &self.receipt {
+            receipt.record(record);
+        }
+    }
+
+    fn record_manifest(&self, manifest: &Manifest, source: Source) {
+        for record in Record::from_manifest(manifest, Some(&source)) {
+            self.record(record);
+        }
+    }
+
+    /// Downloads `url` into `cache_dir`, unless a copy (matching `sha256`)
+    /// is already there, and returns its path
+    pub fn fetch(&self, url: &Url, sha256: Option<&str>) -> Result<PathBuf, Error> {
+        let options = self.stream_options(url, sha256)?;
+        let path = PathBuf::from(options.keep_copy.clone().unwrap_or_default());
+        let download = Action::Download {
+            url: url.to_string(),
+            to: path.clone(),
+        };
+        if options.cached_copy()?.is_none() {
+            self.check_online(url)?;
+            if self.planned(download) {
+                return Ok(path);
+            }
+            self.log(&format!("downloading {}", url));
+            let mut body = stream(url, &options)?;
+            io::copy(&mut body, &mut io::sink()).map_err(|source| Error::Download {
+                url: url.to_string(),
+                source,
+            })?;
+            body.finish()?;
+        }
+        self.record(Record::Download {
+            url: url.to_string(),
+            path: path.clone(),
+        });
+        Ok(path)
+    }
+
+    /// `fetch`es `url` and copies it to `to`, returning where that really is
+    pub fn download<P: AsRef<Path>>(
+        &self,
+        url: &Url,
+        to: P,
+        sha256: Option<&str>,
+    ) -> Result<PathBuf, Error> {
+        let to = self.resolve(to);
+        if self.repair && to.symlink_metadata().is_ok() {
+            return Ok(to);
+        }
+        let cached = self.fetch(url, sha256)?;
+        if let Some(parent) = to.parent().filter(|p| !p.as_os_str().is_empty()) {
+            self.mkdirp(parent)?;
+        }
+        let download = Action::Download {
+            url: url.to_string(),
+            to: to.clone(),
+        };
+        if self.planned(download) {
+            return Ok(to);
+        }
+        let created = to.symlink_metadata().is_err();
+        let size = std::fs::copy(&cached, &to).at(&to)?;
+        self.observers
+            .notify(&Event::FileWritten { path: &to, size });
+        if created {
+            self.record(Record::File {
+                path: to.clone(),
+                source: Some(Source::Download {
+                    url: url.to_string(),
+                    sha256: sha256.map(String::from),
+                }),
+            });
+        }
+        Ok(to)
+    }
+
+    /// `archive::untar_url` with this context's cache and extract options
+    pub fn untar_url<E>(
+        &self,
+        url: &Url,
+        extract_dir: E,
+        sha256: Option<&str>,
+    ) -> Result<Manifest, Error>
+    where
+        E: Into<OsString>,
+    {
+        let options = self.stream_options(url, sha256)?;
+        let to = self.resolve(PathBuf::from(extract_dir.into()));
+        let cached = options.cached_copy()?.is_some();
+        if !cached {
+            self.check_online(url)?;
+        }
+        if self.is_dry_run() && !cached {
+            self.planned(Action::Download {
+                url: url.to_string(),
+                to: PathBuf::from(options.keep_copy.clone().unwrap_or_default()),
+            });
+        }
+        let extract = Action::Extract {
+            archive: url.to_string(),
+            to: to.clone(),
+        };
+        if self.planned(extract) {
+            return Ok(Manifest {
+                root: to,
+                ..Manifest::default()
+            });
+        }
+        self.log(&format!("extracting {}", url));
+        self.mkdirp(&to)?;
+        let manifest = untar_url(url, Some(&to), &self.extract_options(), &options)?;
+        self.record_manifest(
+            &manifest,
+            Source::ArchiveUrl {
+                url: url.to_string(),
+                sha256: sha256.map(String::from),
+                to,
+            },
+        );
+        Ok(manifest)
+    }
+
+    /// `archive::untar_with_options` with this context's extract options
+    pub fn untar<F, E>(&self, tarfile: F, extract_dir: E) -> Result<Manifest, Error>
+    where
+        F: Into<OsString>,
+        E: Into<OsString>,
+    {
+        let tarfile = PathBuf::from(tarfile.into());
+        let to = self.resolve(PathBuf::from(extract_dir.into()));
+        let extract = Action::Extract {
+            archive: tarfile.display().to_string(),
+            to: to.clone(),
+        };
+        if self.planned(extract) {
+            return Ok(Manifest {
+                root: to,
+                ..Manifest::default()
+            });
+        }
+        self.mkdirp(&to)?;
+        let manifest = untar_with_options(&tarfile, Some(&to), &self.extract_options())?;
+        self.record_manifest(
+            &manifest,
+            Source::Archive {
+                archive: tarfile,
+                to,
+            },
+        );
+        Ok(manifest)
+    }
+
+    pub fn mkdirp<P: AsRef<Path>>(&self, path: P) -> Result<(), Error> {
+        let path = &self.resolve(path);
+        if path.is_dir()
+            || self.planned(Action::CreateDir {
+                path: path.to_path_buf(),
+            })
+        {
+            return Ok(());
+        }
+        let missing: Vec<&Path> = path
+            .ancestors()
+            .take_while(|p| !p.as_os_str().is_empty() && !p.exists())
+            .collect();
+        mkdirp(path)?;
+        for dir in missing.into_iter().rev() {
+            self.record(Record::Directory {
+                path: dir.to_path_buf(),
+                source: None,
+            });
+        }
+        Ok(())
+    }
+
+    /// Writes `contents` to `path`, creating its parent directories
+    pub fn write<P, C>(&self, path: P, contents: C) -> Result<(), Error>
+    where
+        P: AsRef<Path>,
+        C: AsRef<[u8]>,
+    {
+        let (path, contents) = (&self.resolve(path), contents.as_ref());
+        if self.repair && path.symlink_metadata().is_ok() {
+            return Ok(());
+        }
+        if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+            self.mkdirp(parent)?;
+        }
+        let write = Action::WriteFile {
+            path: path.to_path_buf(),
+            size: contents.len() as u64,
+        };
+        if !self.planned(write) {
+            // Files that were already there aren't ours to remove
+            let created = path.symlink_metadata().is_err();
+            std::fs::write(path, contents).at(path)?;
+            self.observers.notify(&Event::FileWritten {
+                path,
+                size: contents.len() as u64,
+            });
+            if created {
+                self.record(Record::File {
+                    path: path.to_path_buf(),
+                    source: std::str::from_utf8(contents).ok().map(|text| Source::Text {
+                        contents: text.to_string(),
+                    }),
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Sets an environment variable of this process. The environment is
+    /// shared by every register, including others in the same parallel
+    /// `Orchestrator` wave: calls are serialised with `env_var`, but the
+    /// receipt's `previous` may be another register's value.
+    pub fn set_env<K, V>(&self, key: K, value: V)
+    where
+        K: AsRef<OsStr>,
+        V: AsRef<OsStr>,
+    {
+        let (key, value) = (key.as_ref(), value.as_ref());
+        let set = Action::SetEnv {
+            key: key.to_string_lossy().into_owned(),
+            value: value.to_string_lossy().into_owned(),
+        };
+        if !self.planned(set) {
+            let _env = lock_env();
+            self.record(Record::Env {
+                key: key.to_string_lossy().into_owned(),
+                previous: std::env::var(key).ok(),
+            });
+            std::env::set_var(key, value);
+        }
+    }
+
+    /// Reads an environment variable without racing `set_env` in registers
+    /// running alongside this one
+    pub fn env_var<K: AsRef<OsStr>>(&self, key: K) -> Option<OsString> {
+        let _env = lock_env();
+        std::env::var_os(key)
+    }
+
+    fn check_online(&self, url: &Url) -> Result<(), Error> {
+        if self.offline {
+            return Err(Error::Download {
+                url: url.to_string(),
+                source: io::Error::new(io::ErrorKind::NotConnected, "offline, and not cached"),
+            });
+        }
+        Ok(())
+    }
+
+    /// Where `fetch` keeps `url` in `cache_dir`: its file name, prefixed
+    /// with a hash of the whole URL so that files of the same name from
+    /// different places don't share an entry
+    pub fn cached_path(&self, url: &Url) -> Result<PathBuf, Error> {
+        let name = match url.path_segments().and_then(|mut s| s.next_back()) {
+            Some(name) if !name.is_empty() => Ok(name),
+            _ => Err(Error::InvalidUrl {
+                url: url.to_string(),
+                reason: String::from("No filename detectable from URL"),
+            }),
+        }?;
+        let hash = format!("{:x}", Sha256::digest(url.as_str().as_bytes()));
+        Ok(self.cache_dir.join(format!("{}-{}", &hash[..16], name)))
+    }
+
+    fn stream_options(&self, url: &Url, sha256: Option<&str>) -> Result<StreamOptions, Error> {
+        Ok(StreamOptions {
+            sha256: sha256.map(String::from),
+            keep_copy: Some(self.cached_path(url)?.into_os_string()),
+            observers: self.observers.clone(),
+            ..StreamOptions::default()
+        })
+    }
+
+    fn extract_options(&self) -> ExtractOptions {
+        ExtractOptions {
+            observers: self.extract.observers.chain(&self.observers),
+            keep_existing: self.extract.keep_existing || self.repair,
+            ..self.extract.clone()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    use tempfile::TempDir;
+
+    use crate::download::serve_once;
+
+    struct MemLog(Mutex<Vec<String>>);
+
+    impl Log for MemLog {
+        fn log(&self, message: &str) {
+            self.0.lock().unwrap().push(message.to_string());
+        }
+    }
+
+    #[test]
+    fn test_fetch_cached() {
+        let tmp_dir: TempDir = tempfile::Builder::new()
+            .prefix(env!("CARGO_PKG_NAME"))
+            .tempdir()
+            .unwrap();
+        let log = Arc::new(MemLog(Mutex::new(Vec::new())));
+        let ctx = Context {
+            cache_dir: tmp_dir.path().join("cache"),
+            logger: log.clone(),
+            ..Context::default()
+        };
+
+        let url = serve_once(b"payload".to_vec()).join("tool.bin").unwrap();
+        let path = ctx.fetch(&url, None).unwrap();
+        assert_eq!(path, ctx.cached_path(&url).unwrap());
+        assert!(path.starts_with(tmp_dir.path().join("cache")));
+        assert!(path.to_string_lossy().ends_with("-tool.bin"));
+        assert_eq!(std::fs::read(&path).unwrap(), b"payload");
+
+        // The server only answers once, so this has to come from the cache
+        assert_eq!(ctx.fetch(&url, None).unwrap(), path);
+        assert_eq!(log.0.lock().unwrap().len(), 1);
+
+        let offline = Context {
+            offline: true,
+            ..ctx.clone()
+        };
+        assert_eq!(offline.fetch(&url, None).unwrap(), path);
+        // Same name, somewhere else: not the cached copy
+        let elsewhere = url.join("v2/tool.bin").unwrap();
+        assert_ne!(ctx.cached_path(&elsewhere).unwrap(), path);
+        assert!(offline.fetch(&elsewhere, None).is_err());
+        let missing = url.join("missing.bin").unwrap();
+        assert!(matches!(
+            offline.fetch(&missing, None),
+            Err(Error::Download { .. })
+        ));
+    }
+
+    #[test]
+    fn test_root() {
+        let tmp_dir: TempDir = tempfile::Builder::new()
+            .prefix(env!("CARGO_PKG_NAME"))
+            .tempdir()
+            .unwrap();
+        let root = tmp_dir.path().join("root");
+        let ctx = Context {
+            cache_dir: tmp_dir.path().join("cache"),
+            root: Some(root.clone()),
+            ..Context::default()
+        };
+
+        ctx.write("/etc/app/app.conf", "on\n").unwrap();
+        assert_eq!(
+            std::fs::read(root.join("etc/app/app.conf")).unwrap(),
+            b"on\n"
+        );
+        ctx.mkdirp("/var/lib/app").unwrap();
+        assert!(root.join("var/lib/app").is_dir());
+
+        let url = serve_once(b"#!/bin/sh\n".to_vec()).join("app").unwrap();
+        let bin = ctx.download(&url, "/usr/bin/app", None).unwrap();
+        assert_eq!(bin, root.join("usr/bin/app"));
+        assert_eq!(std::fs::read(&bin).unwrap(), b"#!/bin/sh\n");
+        assert_eq!(
+            ctx.fetch(&url, None).unwrap(),
+            ctx.cached_path(&url).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_env() {
+        let recording = Arc::new(Recording::default());
+        let ctx = Context {
+            receipt: Some(recording.clone()),
+            ..Context::default()
+        };
+        // Registers of one parallel wave setting the same variable
+        std::thread::scope(|s| {
+            for value in ["1", "2"] {
+                let ctx = &ctx;
+                s.spawn(move || ctx.set_env("OFFREGISTERS_CONTEXT_ENV_TEST", value));
+            }
+        });
+        let value = ctx.env_var("OFFREGISTERS_CONTEXT_ENV_TEST").unwrap();
+        assert!(value == "1" || value == "2");
+
+        // Each saw the environment before or after the other, never between
+        let mut previous: Vec<Option<String>> = recording
+            .take()
+            .into_iter()
+            .map(|record| match record {
+                Record::Env { previous, .. } => previous,
+                record => panic!("expected an env record, got {:?}", record),
+            })
+            .collect();
+        previous.sort();
+        assert_eq!(previous[0], None);
+        assert_ne!(previous[1].as_deref(), Some(value.to_str().unwrap()));
+        std::env::remove_var("OFFREGISTERS_CONTEXT_ENV_TEST");
+    }
+}
@@ -1,23 +1,64 @@
 use std::collections::HashMap;
 use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Write};
 use std::path::Path;
 
-use mio_httpc::{CallBuilder, Headers, Httpc, HttpcCfg, SimpleCall};
+use mio_httpc::{
+    Call, CallBuilder, Headers, Httpc, HttpcCfg, RecvState, ResponseBody, SendState, SimpleCall,
+};
 
 use mio::{Events, Poll};
 
 use url::Url;
 
-use failure::Error;
+use sha2::{Digest, Sha256};
 
 use std::ffi::OsString;
 use std::path::PathBuf;
 
-#[derive(Debug, Fail)]
-#[fail(display = "request timed out")]
-pub struct RequestTimeoutError; /* {
-                                    url: Url,
-                                }*/
+use crate::error::{Error, IoContext};
+use crate::event::{Event, Observers};
+use crate::fs::{mkdirp, sha256sum};
+
+#[derive(Debug)]
+pub struct ChecksumMismatchError {
+    pub url: String,
+    pub expected: String,
+    pub actual: String,
+}
+
+impl fmt::Display for ChecksumMismatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "checksum mismatch for {}: expected sha256 {}, got {}",
+            self.url, self.expected, self.actual
+        )
+    }
+}
+
+impl std::error::Error for ChecksumMismatchError {}
+
+fn call_error(url: &str, e: mio_httpc::Error) -> Error {
+    Error::Download {
+        url: url.to_string(),
+        source: io::Error::other(e.to_string()),
+    }
+}
+
+/// Errors from reading a response body, which timeouts are reported through
+fn read_error(url: &str, source: io::Error) -> Error {
+    match source.kind() {
+        io::ErrorKind::TimedOut => Error::Timeout {
+            url: url.to_string(),
+        },
+        _ => Error::Download {
+            url: url.to_string(),
+            source,
+        },
+    }
+}
 
 #[derive(Clone)]
 pub struct DownloadResponse<'a> {
@@ -28,11 +69,11 @@ pub struct DownloadResponse<'a> {
 }
 
 impl<'a> DownloadResponse<'a> {
-    fn response_text(&self) -> Result<String, Error> {
+    pub fn response_text(&self) -> Result<String, Error> {
         if self.raw.is_some() {
-            Ok(String::from_utf8(self.raw.clone().unwrap())?)
+            String::from_utf8(self.raw.clone().unwrap()).map_err(Error::other)
         } else {
-            Err(format_err!("empty response"))
+            Err(Error::msg("empty response"))
         }
     }
 }
@@ -61,6 +102,7 @@ fn do_call<'a>(
     htp: &mut Httpc,
     poll: &Poll,
     mut call: SimpleCall,
+    url: &str,
 ) -> Result<DownloadResponse<'a>, Error> {
     let to = ::std::time::Duration::from_millis(100);
     let mut events = Events::with_capacity(8);
@@ -74,17 +116,19 @@ fn do_call<'a>(
         poll.poll(&mut events, Some(to))?;
         for cref in htp.timeout().into_iter() {
             if call.is_ref(cref) {
-                return Err(RequestTimeoutError.into());
+                return Err(Error::Timeout {
+                    url: url.to_string(),
+                });
             }
         }
 
         for ev in events.iter() {
             let cref = htp.event(&ev);
 
-            if call.is_call(&cref) && call.perform(htp, &poll)? {
+            if call.is_call(&cref) && call.perform(htp, poll).map_err(|e| call_error(url, e))? {
                 let (response, body) = match call.finish() {
                     Some(rb) => Ok(rb),
-                    None => Err(format_err!("No response")),
+                    None => Err(read_error(url, io::Error::other("no response"))),
                 }?;
                 raw = Some(body);
                 last_status = response.status;
@@ -101,6 +145,14 @@ fn do_call<'a>(
     })
 }
 
+#[derive(Clone, Debug, Default)]
+pub struct DownloadOptions {
+    /// Download again even if `target_dir` already has the file
+    pub upsert: bool,
+    /// Told of `Event::DownloadProgress` once each body has arrived
+    pub observers: Observers,
+}
+
 pub fn download<'a, D>(
     target_dir: Option<D>,
     urls: Vec<Url>,
@@ -109,31 +161,39 @@ pub fn download<'a, D>(
 where
     D: Into<OsString>,
 {
+    download_with_options(
+        target_dir,
+        urls,
+        &DownloadOptions {
+            upsert,
+            ..DownloadOptions::default()
+        },
+    )
+}
+
+pub fn download_with_options<'a, D>(
+    target_dir: Option<D>,
+    urls: Vec<Url>,
+    options: &DownloadOptions,
+) -> Result<HashMap<Url, DownloadResponse<'a>>, Error>
+where
+    D: Into<OsString>,
+{
+    let upsert = options.upsert;
     let mut url2response: HashMap<Url, DownloadResponse> = HashMap::new();
 
-    let dir: Option<OsString> = match target_dir {
-        Some(d) => Some(d.into()),
-        None => None,
-    };
-    let dir_is_some = dir.is_some().clone();
+    let dir: Option<OsString> = target_dir.map(|d| d.into());
+    let dir_is_some = dir.is_some();
 
     let poll = Poll::new()?;
 
-    let cfg = match HttpcCfg::certs_from_path(".") {
-        Ok(cfg) => cfg,
-        Err(_) => Default::default(),
-    };
+    let cfg = HttpcCfg::certs_from_path(".").unwrap_or_default();
     let mut htp = Httpc::new(10, Some(cfg));
 
-    let _base = if dir_is_some {
-        dir.unwrap()
-    } else {
-        OsString::default()
-    };
+    let _base = dir.unwrap_or_default();
     let base = Path::new(&_base);
 
-    for i in 0..urls.len() {
-        let url = &urls[i];
+    for url in &urls {
         let url_s = url.clone().into_string();
 
         let call = CallBuilder::get()
@@ -142,7 +202,8 @@ where
             .timeout_ms(10000)
             .max_response(1024 * 1024 * 20) // 20MB
             // .insecure_do_not_verify_domain()
-            .simple_call(&mut htp, &poll)?; // .expect("Call start failed");
+            .simple_call(&mut htp, &poll)
+            .map_err(|e| call_error(&url_s, e))?;
 
         let mut error: Option<Error> = None;
         let download_path: Option<String> = if dir_is_some {
@@ -151,10 +212,10 @@ where
                 .to_string_lossy()
                 .into_owned();
             if p.is_empty() {
-                error = Some(format_err!(
-                    "Conversion to filename failed for: {:#?}",
-                    urls[i]
-                ));
+                error = Some(Error::InvalidUrl {
+                    url: url_s.clone(),
+                    reason: String::from("Conversion to filename failed"),
+                });
                 None
             } else {
                 Some(p)
@@ -165,10 +226,13 @@ where
 
         let to_file = dir_is_some && download_path.is_some();
 
-        if error.is_some() {
-            return Err(error.unwrap());
+        if let Some(e) = error {
+            return Err(e);
         } else if dir_is_some && download_path.is_none() {
-            return Err(format_err!("No filename detectable from URL"));
+            return Err(Error::InvalidUrl {
+                url: url_s,
+                reason: String::from("No filename detectable from URL"),
+            });
         }
 
         let (k, v) = ({
@@ -179,7 +243,7 @@ where
             };
 
             enum Next<'g> {
-                Ok((Url, DownloadResponse<'g>)),
+                Ok(Box<(Url, DownloadResponse<'g>)>),
                 // Err(Error),
                 ServerRequest(Option<PathBuf>),
             }
@@ -188,15 +252,15 @@ where
                 Some(download_path_p) => {
                     if download_path_p.exists() && !upsert {
                         match std::fs::read(&download_path_p) {
-                            Ok(raw) => Next::Ok((
-                                urls[i].clone(),
+                            Ok(raw) => Next::Ok(Box::new((
+                                url.clone(),
                                 DownloadResponse {
                                     headers: Headers::default(),
                                     status: 200,
                                     raw: Some(raw),
                                     downloaded_to: Some(download_path_p.into()),
                                 },
-                            )),
+                            ))),
                             Err(e) => {
                                 eprintln!(
                                     "Received error while reading, downloading again. Error was: {}",
@@ -210,27 +274,32 @@ where
                 }
                 None => Next::ServerRequest(None),
             } {
-                Next::Ok((url, res)) => Ok((url, res)),
+                Next::Ok(url_res) => Ok(*url_res),
                 // Next::Err(e) => Err(e),
-                Next::ServerRequest(dp_p_opt) => match do_call(&mut htp, &poll, call) {
-                    Ok(download_response) => {
-                        match if download_response.raw.is_none() {
-                            Err(format_err!("Empty response from URL"))
-                        } else if to_file {
-                            let dp_p = dp_p_opt.unwrap();
-
-                            let victor = download_response.raw.unwrap();
-                            std::fs::write(&dp_p, &victor)?;
-                            Ok(DownloadResponse {
-                                headers: download_response.headers,
-                                status: download_response.status,
-                                raw: Some(victor),
-                                downloaded_to: Some(dp_p.into()),
-                            })
-                        } else {
-                            Ok(download_response)
+                Next::ServerRequest(dp_p_opt) => match do_call(&mut htp, &poll, call, &url_s) {
+                    Ok(mut download_response) => {
+                        match match download_response.raw.take() {
+                            None => Err(Error::Download {
+                                url: url_s.clone(),
+                                source: io::Error::other("empty response"),
+                            }),
+                            Some(victor) => {
+                                options.observers.notify(&Event::DownloadProgress {
+                                    url: &url_s,
+                                    received: victor.len() as u64,
+                                    total: Some(victor.len() as u64),
+                                });
+                                if to_file {
+                                    let dp_p = dp_p_opt.unwrap();
+
+                                    std::fs::write(&dp_p, &victor).at(&dp_p)?;
+                                    download_response.downloaded_to = Some(dp_p.into());
+                                }
+                                download_response.raw = Some(victor);
+                                Ok(download_response)
+                            }
                         } {
-                            Ok(v) => Ok((urls[i].clone(), v)),
+                            Ok(v) => Ok((url.clone(), v)),
                             Err(e) => Err(e),
                         }
                     }
@@ -246,6 +315,318 @@ where
     Ok(url2response)
 }
 
+#[derive(Clone, Debug)]
+pub struct StreamOptions {
+    /// Expected hex sha256 of the body, checked by `DownloadStream::finish`
+    pub sha256: Option<String>,
+    /// Also write the body here; reused instead of downloading again if it
+    /// already exists (and matches `sha256`)
+    pub keep_copy: Option<OsString>,
+    pub timeout_ms: u64,
+    /// Told of `Event::DownloadProgress` as the body comes in
+    pub observers: Observers,
+}
+
+impl Default for StreamOptions {
+    fn default() -> Self {
+        StreamOptions {
+            sha256: None,
+            keep_copy: None,
+            timeout_ms: 10 * 60 * 1000,
+            observers: Observers::default(),
+        }
+    }
+}
+
+impl StreamOptions {
+    pub fn cached_copy(&self) -> Result<Option<PathBuf>, Error> {
+        match &self.keep_copy {
+            Some(p) if Path::new(p).exists() => match &self.sha256 {
+                Some(expected) if !sha256sum(p)?.eq_ignore_ascii_case(expected) => Ok(None),
+                _ => Ok(Some(PathBuf::from(p))),
+            },
+            _ => Ok(None),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum StreamPhase {
+    Sending,
+    Receiving,
+    Done,
+}
+
+/// Response body of a GET, read incrementally rather than buffered in memory
+pub struct DownloadStream {
+    htp: Httpc,
+    poll: Poll,
+    events: Events,
+    call: Call,
+    phase: StreamPhase,
+    status: u16,
+    remaining: Option<usize>,
+    total: Option<u64>,
+    received: u64,
+    buf: Vec<u8>,
+    pos: usize,
+    hasher: Sha256,
+    url: String,
+    expected_sha256: Option<String>,
+    copy: Option<(PathBuf, File)>,
+    observers: Observers,
+}
+
+pub fn stream(url: &Url, options: &StreamOptions) -> Result<DownloadStream, Error> {
+    let poll = Poll::new()?;
+    let cfg = HttpcCfg::certs_from_path(".").unwrap_or_default();
+    let mut htp = Httpc::new(10, Some(cfg));
+    let call = CallBuilder::get()
+        .url(url.as_str())
+        .and_then(|builder| builder.timeout_ms(options.timeout_ms).call(&mut htp, &poll))
+        .map_err(|e| call_error(url.as_str(), e))?;
+
+    let copy = match &options.keep_copy {
+        Some(p) => {
+            let path = PathBuf::from(p);
+            if let Some(parent) = path.parent() {
+                mkdirp(parent)?;
+            }
+            let part = part_path(&path);
+            let file = File::create(&part).at(&part)?;
+            Some((path, file))
+        }
+        None => None,
+    };
+
+    let mut stream = DownloadStream {
+        htp,
+        poll,
+        events: Events::with_capacity(8),
+        call,
+        phase: StreamPhase::Sending,
+        status: 0,
+        remaining: None,
+        total: None,
+        received: 0,
+        buf: Vec::new(),
+        pos: 0,
+        hasher: Sha256::new(),
+        url: url.to_string(),
+        expected_sha256: options.sha256.clone(),
+        copy,
+        observers: options.observers.clone(),
+    };
+    stream.fill().map_err(|e| read_error(url.as_str(), e))?;
+    if !(200..300).contains(&stream.status) {
+        stream.discard_copy();
+        return Err(Error::HttpStatus {
+            url: url.to_string(),
+            status: stream.status,
+        });
+    }
+    Ok(stream)
+}
+
+fn part_path(path: &Path) -> PathBuf {
+    let mut part = path.as_os_str().to_owned();
+    part.push(".part");
+    PathBuf::from(part)
+}
+
+impl DownloadStream {
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    /// Reads whatever the consumer left unread, checks the digest, and moves
+    /// the kept copy into place
+    pub fn finish(mut self) -> Result<(), Error> {
+        io::copy(&mut self, &mut io::sink()).map_err(|e| read_error(&self.url, e))?;
+        let actual = format!("{:x}", self.hasher.clone().result());
+        if let Some(expected) = self.expected_sha256.take() {
+            if !actual.eq_ignore_ascii_case(&expected) {
+                self.discard_copy();
+                return Err(ChecksumMismatchError {
+                    url: self.url.clone(),
+                    expected,
+                    actual,
+                }
+                .into());
+            }
+        }
+        if let Some((path, file)) = self.copy.take() {
+            file.sync_all().at(part_path(&path))?;
+            std::fs::rename(part_path(&path), &path).at(&path)?;
+        }
+        Ok(())
+    }
+
+    fn discard_copy(&mut self) {
+        if let Some((path, _)) = self.copy.take() {
+            let _ = std::fs::remove_file(part_path(&path));
+        }
+    }
+
+    /// Drives the call until more body is buffered or the response is complete
+    fn fill(&mut self) -> io::Result<()> {
+        let to = ::std::time::Duration::from_millis(100);
+        let received = self.buf.len();
+        loop {
+            match self.phase {
+                StreamPhase::Sending => {
+                    match self.htp.call_send(&self.poll, &mut self.call, None) {
+                        SendState::Receiving => {
+                            self.phase = StreamPhase::Receiving;
+                            continue;
+                        }
+                        SendState::Done => self.phase = StreamPhase::Done,
+                        SendState::Error(e) => return Err(io::Error::other(e.to_string())),
+                        SendState::WaitReqBody => {
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidInput,
+                                "request body required",
+                            ))
+                        }
+                        SendState::Wait | SendState::SentBody(_) => {}
+                    }
+                }
+                StreamPhase::Receiving => {
+                    match self
+                        .htp
+                        .call_recv(&self.poll, &mut self.call, Some(&mut self.buf))
+                    {
+                        RecvState::Response(response, body) => {
+                            self.status = response.status;
+                            if let ResponseBody::Sized(size) = body {
+                                self.remaining = Some(size);
+                                self.total = Some(size as u64);
+                            }
+                            if body.is_empty() {
+                                self.phase = StreamPhase::Done;
+                            }
+                            continue;
+                        }
+                        // mio_httpc keeps handing back the tail of the body once
+                        // it's complete instead of reporting Done, so stop at
+                        // Content-Length, or at the first empty read if streamed
+                        RecvState::ReceivedBody(0) => self.phase = StreamPhase::Done,
+                        RecvState::ReceivedBody(_) => {
+                            if let Some(remaining) = self.remaining {
+                                let n = remaining.min(self.buf.len() - received);
+                                self.buf.truncate(received + n);
+                                self.remaining = Some(remaining - n);
+                                if remaining == n {
+                                    self.phase = StreamPhase::Done;
+                                }
+                            }
+                            if self.buf.len() == received && self.phase != StreamPhase::Done {
+                                continue;
+                            }
+                        }
+                        RecvState::DoneWithBody(body) => {
+                            self.buf.extend(body);
+                            self.phase = StreamPhase::Done;
+                        }
+                        RecvState::Done => self.phase = StreamPhase::Done,
+                        RecvState::Sending => {
+                            self.phase = StreamPhase::Sending;
+                            continue;
+                        }
+                        RecvState::Error(e) => return Err(io::Error::other(e.to_string())),
+                        RecvState::Wait => {}
+                    }
+                }
+                StreamPhase::Done => {}
+            }
+
+            if self.buf.len() > received || self.phase == StreamPhase::Done {
+                break;
+            }
+
+            self.poll.poll(&mut self.events, Some(to))?;
+            for cref in self.htp.timeout().into_iter() {
+                if self.call.is_ref(cref) {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, "request timed out"));
+                }
+            }
+            for ev in self.events.iter() {
+                self.htp.event(&ev);
+            }
+        }
+
+        let new = &self.buf[received..];
+        self.hasher.input(new);
+        if let Some((_, file)) = &mut self.copy {
+            file.write_all(new)?;
+        }
+        if !new.is_empty() {
+            self.received += new.len() as u64;
+            self.observers.notify(&Event::DownloadProgress {
+                url: &self.url,
+                received: self.received,
+                total: self.total,
+            });
+        }
+        Ok(())
+    }
+}
+
+// A stream dropped before `finish` leaves no `.part` file behind
+impl Drop for DownloadStream {
+    fn drop(&mut self) {
+        self.discard_copy();
+    }
+}
+
+impl Read for DownloadStream {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.buf.len() {
+            self.buf.clear();
+            self.pos = 0;
+            if self.phase == StreamPhase::Done {
+                return Ok(0);
+            }
+            self.fill()?;
+        }
+        let n = out.len().min(self.buf.len() - self.pos);
+        out[..n].copy_from_slice(&self.buf[self.pos..self.pos + n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+/// Serves `body` to a single GET on localhost, for tests that can't rely on the network
+#[cfg(test)]
+pub(crate) fn serve_once(body: Vec<u8>) -> Url {
+    use std::io::BufRead;
+    use std::net::TcpListener;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = Url::parse(&format!(
+        "http://{}/archive.tar.gz",
+        listener.local_addr().unwrap()
+    ))
+    .unwrap();
+    std::thread::spawn(move || {
+        let (stream, _) = listener.accept().unwrap();
+        let mut reader = io::BufReader::new(stream.try_clone().unwrap());
+        let mut line = String::new();
+        while reader.read_line(&mut line).unwrap() > 2 {
+            line.clear();
+        }
+        let mut stream = stream;
+        write!(
+            stream,
+            "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+            body.len()
+        )
+        .unwrap();
+        stream.write_all(&body).unwrap();
+    });
+    url
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -253,7 +634,7 @@ mod tests {
     use std::env::temp_dir;
     use std::ffi::OsString;
     use std::fs::metadata;
-    use std::sync::Mutex;
+    use std::sync::{Arc, Mutex};
 
     use std::time::SystemTime;
     use tempfile::Builder;
@@ -268,20 +649,13 @@ mod tests {
 
     #[inline(always)]
     fn error_handler(error: Error) {
-        if error.downcast_ref::<RequestTimeoutError>().is_none() {
-            let fail = error.as_fail();
-            eprintln!(
-                "fail.cause(): {:#?}, fail.backtrace(): {:#?}, fail: {:#?}, name: {:#?}",
-                fail.cause(),
-                fail.backtrace(),
-                fail,
-                fail.name()
-            );
-            panic!(error)
+        if !matches!(error, Error::Timeout { .. }) {
+            eprintln!("error: {:#?}", error);
+            panic!("{}", error)
         }
     }
 
-    const URLRESPONSES: &'static [&'static UrlResponse] = &[
+    const URLRESPONSES: &[&UrlResponse] = &[
         &UrlResponse {
             url: "http://detectportal.firefox.com/success.txt",
             status: 200,
@@ -334,7 +708,7 @@ mod tests {
             Ok(url2response) => {
                 for &expected_url_response in URLRESPONSES {
                     let url: &Url = &Url::parse(expected_url_response.url).unwrap();
-                    assert_eq!(url2response.contains_key(url), true);
+                    assert!(url2response.contains_key(url));
                     let actual_response = url2response.get(url).unwrap();
                     assert_eq!(
                         actual_response.downloaded_to.clone().unwrap(),
@@ -356,7 +730,7 @@ mod tests {
             .prefix(env!("CARGO_PKG_NAME"))
             .tempdir()
             .unwrap();
-        let tmp_dir_os_string = tmp_dir.into_path().into_os_string();
+        let tmp_dir_os_string = tmp_dir.path().as_os_str().to_owned();
 
         fn download_for_cache(dir: &OsString) {
             let urls = urls2urls();
@@ -364,12 +738,12 @@ mod tests {
                 Ok(url2response) => {
                     for &expected_url_response in URLRESPONSES {
                         let url: Url = Url::parse(expected_url_response.url).unwrap();
-                        assert_eq!(url2response.contains_key(&url), true);
+                        assert!(url2response.contains_key(&url));
                         let actual_response = url2response.get(&url).unwrap();
 
                         let path = Path::new(dir).join(expected_url_response.fname);
 
-                        assert_eq!(path.exists(), true);
+                        assert!(path.exists());
 
                         let path_os_string = path.into_os_string();
 
@@ -402,7 +776,7 @@ mod tests {
                 }
                 Err(e) => error_handler(e),
             }
-        };
+        }
         download_for_cache(&tmp_dir_os_string); // Download, filling cache
         download_for_cache(&tmp_dir_os_string); // Try download, find in cache, use that instead
                                                 // std::fs::remove_dir_all(TMP_DIR.as_path()).unwrap();
@@ -414,7 +788,7 @@ mod tests {
             Ok(url2response) => {
                 for &expected_url_response in URLRESPONSES {
                     let url: &Url = &Url::parse(expected_url_response.url).unwrap();
-                    assert_eq!(url2response.contains_key(url), true);
+                    assert!(url2response.contains_key(url));
                     let actual_response = url2response.get(url).unwrap();
                     assert_eq!(
                         actual_response.response_text().unwrap(),
@@ -426,4 +800,96 @@ mod tests {
             Err(e) => error_handler(e),
         }
     }
+
+    #[test]
+    fn download_progress() {
+        let body = vec![7u8; 5000];
+        let url = serve_once(body.clone());
+        let progress = Arc::new(Mutex::new(Vec::new()));
+        let mut options = DownloadOptions::default();
+        let seen = progress.clone();
+        options.observers.push(move |event: &Event| {
+            if let Event::DownloadProgress {
+                received, total, ..
+            } = event
+            {
+                seen.lock().unwrap().push((*received, *total));
+            }
+        });
+
+        let url2response =
+            download_with_options(None as Option<&str>, vec![url.clone()], &options).unwrap();
+        assert_eq!(url2response[&url].raw.as_deref(), Some(&body[..]));
+        assert_eq!(*progress.lock().unwrap(), vec![(5000, Some(5000))]);
+    }
+
+    #[test]
+    fn stream_to_mem() {
+        let body: Vec<u8> = (0..100_000).map(|i| (i % 251) as u8).collect();
+        let url = serve_once(body.clone());
+        let tmp_dir = Builder::new()
+            .prefix(env!("CARGO_PKG_NAME"))
+            .tempdir()
+            .unwrap();
+        let keep = tmp_dir.path().join("cache").join("body.bin");
+        let progress = Arc::new(Mutex::new(Vec::new()));
+        let mut options = StreamOptions {
+            sha256: Some(format!("{:x}", Sha256::digest(&body))),
+            keep_copy: Some(keep.clone().into_os_string()),
+            ..StreamOptions::default()
+        };
+        let seen = progress.clone();
+        options.observers.push(move |event: &Event| {
+            if let Event::DownloadProgress {
+                received, total, ..
+            } = event
+            {
+                seen.lock().unwrap().push((*received, *total));
+            }
+        });
+
+        let mut stream = stream(&url, &options).unwrap();
+        assert_eq!(stream.status(), 200);
+        let mut received = Vec::new();
+        stream.read_to_end(&mut received).unwrap();
+        stream.finish().unwrap();
+
+        assert_eq!(received, body);
+        assert_eq!(std::fs::read(&keep).unwrap(), body);
+        assert_eq!(options.cached_copy().unwrap(), Some(keep));
+        let progress = progress.lock().unwrap();
+        assert!(progress.windows(2).all(|w| w[0].0 < w[1].0));
+        assert_eq!(progress.last(), Some(&(100_000, Some(100_000))));
+    }
+
+    #[test]
+    fn stream_checksum_mismatch() {
+        let url = serve_once(b"not what you expected".to_vec());
+        let options = StreamOptions {
+            sha256: Some(String::from("00")),
+            ..StreamOptions::default()
+        };
+        let error = stream(&url, &options).unwrap().finish().unwrap_err();
+        match error {
+            Error::Checksum(e) => assert_eq!(e.url, url.as_str()),
+            e => panic!("expected a checksum mismatch, got {}", e),
+        }
+    }
+
+    #[test]
+    fn stream_dropped() {
+        let url = serve_once(b"never finished".to_vec());
+        let tmp_dir = Builder::new()
+            .prefix(env!("CARGO_PKG_NAME"))
+            .tempdir()
+            .unwrap();
+        let keep = tmp_dir.path().join("body.bin");
+        let options = StreamOptions {
+            keep_copy: Some(keep.clone().into_os_string()),
+            ..StreamOptions::default()
+        };
+        drop(stream(&url, &options).unwrap());
+        assert!(!part_path(&keep).exists());
+        assert!(!keep.exists());
+    }
 }
@@ -9,13 +9,13 @@ where
 }
 
 pub fn temp_dir_osstring() -> std::ffi::OsString {
-    return std::env::temp_dir().into_os_string().to_owned();
+    std::env::temp_dir().into_os_string()
 }
 
 pub fn temp_dir_string() -> String {
     let _td = std::env::temp_dir();
     let _td_cow = _td.to_string_lossy();
-    return _td_cow.as_ref().to_owned();
+    _td_cow.as_ref().to_owned()
 }
 
 #[cfg(test)]
@@ -26,18 +26,18 @@ mod tests {
     use std::sync::Arc;
     use std::sync::Mutex;
 
-    const KEY: &'static str = "FOO";
-    const VALUE: &'static str = "BAR";
+    const KEY: &str = "FOO";
+    const VALUE: &str = "BAR";
     lazy_static! {
-        static ref MUTEX: Arc<Mutex<u8>> = Arc::new(Mutex::new(0 as u8));
+        static ref MUTEX: Arc<Mutex<u8>> = Arc::new(Mutex::new(0_u8));
     }
 
-    fn run_test<T>(test: T) -> ()
+    fn run_test<T>(test: T)
     where
-        T: FnOnce() -> () + panic::UnwindSafe,
+        T: FnOnce() + panic::UnwindSafe,
     {
         let m = MUTEX.lock().unwrap();
-        let result = panic::catch_unwind(|| test());
+        let result = panic::catch_unwind(test);
         drop(m);
 
         assert!(result.is_ok())
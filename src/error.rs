@@ -0,0 +1,266 @@
+use std::error::Error as StdError;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::archive::{ArchiveCollisionError, ExtractLimitError, MissingEntriesError};
+use crate::config::Origin;
+use crate::download::ChecksumMismatchError;
+use crate::graph::{CycleError, UnknownDependencyError};
+
+/// Everything that can go wrong in this crate, and in registers built on it
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum Error {
+    /// Reading or writing `path`, when known
+    Io {
+        path: Option<PathBuf>,
+        source: io::Error,
+    },
+    /// The request to `url` failed before a response came back
+    Download {
+        url: String,
+        source: io::Error,
+    },
+    Timeout {
+        url: String,
+    },
+    HttpStatus {
+        url: String,
+        status: u16,
+    },
+    UrlParse(url::ParseError),
+    InvalidUrl {
+        url: String,
+        reason: String,
+    },
+    InvalidPath {
+        path: PathBuf,
+        reason: String,
+    },
+    Checksum(ChecksumMismatchError),
+    /// Not a valid or supported archive or package
+    Archive {
+        path: Option<PathBuf>,
+        reason: String,
+    },
+    Limit(ExtractLimitError),
+    Collision(ArchiveCollisionError),
+    MissingEntries(MissingEntriesError),
+    Pattern(glob::PatternError),
+    Json(serde_json::Error),
+    Env {
+        key: String,
+        reason: String,
+    },
+    /// A bad setting for `register`, and where it came from
+    Config {
+        register: String,
+        key: Option<String>,
+        origin: Origin,
+        reason: String,
+    },
+    Cycle(CycleError),
+    UnknownDependency(UnknownDependencyError),
+    DuplicateRegister(String),
+    UnknownRegister(String),
+    /// Installing `wanted` would replace the newer `installed` version
+    Downgrade {
+        register: String,
+        installed: String,
+        wanted: String,
+    },
+    /// Anything else, typically raised by a register
+    Other(Box<dyn StdError + Send + Sync>),
+}
+
+impl Error {
+    pub fn msg<M: fmt::Display>(message: M) -> Error {
+        Error::Other(message.to_string().into())
+    }
+
+    pub fn other<E>(error: E) -> Error
+    where
+        E: Into<Box<dyn StdError + Send + Sync>>,
+    {
+        Error::Other(error.into())
+    }
+
+    pub(crate) fn archive<P: AsRef<Path>, R: Into<String>>(path: P, reason: R) -> Error {
+        Error::Archive {
+            path: Some(path.as_ref().to_path_buf()),
+            reason: reason.into(),
+        }
+    }
+
+    pub(crate) fn invalid_path<P: AsRef<Path>, R: Into<String>>(path: P, reason: R) -> Error {
+        Error::InvalidPath {
+            path: path.as_ref().to_path_buf(),
+            reason: reason.into(),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Io {
+                path: Some(path),
+                source,
+            } => write!(f, "{}: {}", path.display(), source),
+            Error::Io { path: None, source } => write!(f, "{}", source),
+            Error::Download { url, source } => write!(f, "downloading {}: {}", url, source),
+            Error::Timeout { url } => write!(f, "request to {} timed out", url),
+            Error::HttpStatus { url, status } => {
+                write!(f, "{} responded with HTTP {}", url, status)
+            }
+            Error::UrlParse(e) => write!(f, "invalid URL: {}", e),
+            Error::InvalidUrl { url, reason } => write!(f, "{}: {}", url, reason),
+            Error::InvalidPath { path, reason } => write!(f, "{}: {}", path.display(), reason),
+            Error::Checksum(e) => write!(f, "{}", e),
+            Error::Archive {
+                path: Some(path),
+                reason,
+            } => write!(f, "{}: {}", path.display(), reason),
+            Error::Archive { path: None, reason } => write!(f, "{}", reason),
+            Error::Limit(e) => write!(f, "{}", e),
+            Error::Collision(e) => write!(f, "{}", e),
+            Error::MissingEntries(e) => write!(f, "{}", e),
+            Error::Pattern(e) => write!(f, "invalid pattern: {}", e),
+            Error::Json(e) => write!(f, "{}", e),
+            Error::Env { key, reason } => write!(f, "{}: {}", key, reason),
+            Error::Config {
+                register,
+                key: Some(key),
+                origin,
+                reason,
+            } => write!(f, "{}.{} from {}: {}", register, key, origin, reason),
+            Error::Config { origin, reason, .. } => write!(f, "{}: {}", origin, reason),
+            Error::Cycle(e) => write!(f, "{}", e),
+            Error::UnknownDependency(e) => write!(f, "{}", e),
+            Error::DuplicateRegister(name) => write!(f, "two registers named {:?}", name),
+            Error::UnknownRegister(name) => write!(f, "no register named {:?}", name),
+            Error::Downgrade {
+                register,
+                installed,
+                wanted,
+            } => write!(
+                f,
+                "{}: refusing to downgrade from {} to {}",
+                register, installed, wanted
+            ),
+            Error::Other(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            Error::Io { source, .. } | Error::Download { source, .. } => Some(source),
+            Error::UrlParse(e) => Some(e),
+            Error::Pattern(e) => Some(e),
+            Error::Json(e) => Some(e),
+            Error::Other(e) => e.source(),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(source: io::Error) -> Self {
+        Error::Io { path: None, source }
+    }
+}
+
+impl From<url::ParseError> for Error {
+    fn from(e: url::ParseError) -> Self {
+        Error::UrlParse(e)
+    }
+}
+
+impl From<glob::PatternError> for Error {
+    fn from(e: glob::PatternError) -> Self {
+        Error::Pattern(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Json(e)
+    }
+}
+
+impl From<ChecksumMismatchError> for Error {
+    fn from(e: ChecksumMismatchError) -> Self {
+        Error::Checksum(e)
+    }
+}
+
+impl From<ExtractLimitError> for Error {
+    fn from(e: ExtractLimitError) -> Self {
+        Error::Limit(e)
+    }
+}
+
+impl From<ArchiveCollisionError> for Error {
+    fn from(e: ArchiveCollisionError) -> Self {
+        Error::Collision(e)
+    }
+}
+
+impl From<MissingEntriesError> for Error {
+    fn from(e: MissingEntriesError) -> Self {
+        Error::MissingEntries(e)
+    }
+}
+
+impl From<CycleError> for Error {
+    fn from(e: CycleError) -> Self {
+        Error::Cycle(e)
+    }
+}
+
+impl From<UnknownDependencyError> for Error {
+    fn from(e: UnknownDependencyError) -> Self {
+        Error::UnknownDependency(e)
+    }
+}
+
+/// Attaches the path being worked on to I/O errors
+pub(crate) trait IoContext<T> {
+    fn at<P: AsRef<Path>>(self, path: P) -> Result<T, Error>;
+}
+
+impl<T> IoContext<T> for io::Result<T> {
+    fn at<P: AsRef<Path>>(self, path: P) -> Result<T, Error> {
+        self.map_err(|source| Error::Io {
+            path: Some(path.as_ref().to_path_buf()),
+            source,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_io_context() {
+        let err = std::fs::read("/nonexistent/offregisters")
+            .at("/nonexistent/offregisters")
+            .unwrap_err();
+        assert!(err.to_string().starts_with("/nonexistent/offregisters: "));
+        match &err {
+            Error::Io { path, source } => {
+                assert_eq!(
+                    path.as_deref(),
+                    Some(Path::new("/nonexistent/offregisters"))
+                );
+                assert_eq!(source.kind(), io::ErrorKind::NotFound);
+            }
+            _ => panic!("expected an I/O error"),
+        }
+        assert!(err.source().is_some());
+    }
+}
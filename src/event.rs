@@ -0,0 +1,84 @@
+use std::fmt;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::error::Error;
+use crate::manifest::Manifest;
+use crate::runner::Phase;
+
+/// Something that happened while installing, for `Observer`s
+#[derive(Debug)]
+pub enum Event<'a> {
+    PhaseStarted {
+        register: &'a str,
+        phase: Phase,
+    },
+    PhaseFinished {
+        register: &'a str,
+        phase: Phase,
+        elapsed: Duration,
+    },
+    PhaseFailed {
+        register: &'a str,
+        phase: Phase,
+        elapsed: Duration,
+        error: &'a Error,
+    },
+    /// `received` bytes of the body so far, of `total` if the server said
+    DownloadProgress {
+        url: &'a str,
+        received: u64,
+        total: Option<u64>,
+    },
+    ExtractFinished {
+        archive: &'a str,
+        manifest: &'a Manifest,
+    },
+    FileWritten {
+        path: &'a Path,
+        size: u64,
+    },
+}
+
+/// Cross-cutting behaviour such as logging, timing or auditing, attached to
+/// a `Runner`, `Context`, `StreamOptions` or `ExtractOptions`
+pub trait Observer: Send + Sync {
+    fn event(&self, event: &Event);
+}
+
+impl<F: Fn(&Event) + Send + Sync> Observer for F {
+    fn event(&self, event: &Event) {
+        self(event)
+    }
+}
+
+#[derive(Clone, Default)]
+pub struct Observers(pub Vec<Arc<dyn Observer>>);
+
+impl Observers {
+    pub fn push<O: Observer + 'static>(&mut self, observer: O) {
+        self.0.push(Arc::new(observer));
+    }
+
+    pub fn notify(&self, event: &Event) {
+        for observer in &self.0 {
+            observer.event(event);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// These observers, followed by those of `other`
+    pub fn chain(&self, other: &Observers) -> Observers {
+        Observers(self.0.iter().chain(other.0.iter()).cloned().collect())
+    }
+}
+
+impl fmt::Debug for Observers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Observers({})", self.0.len())
+    }
+}
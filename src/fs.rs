@@ -1,18 +1,68 @@
 use std::ffi::OsString;
-use std::fs::create_dir_all;
-use std::path::Path;
+use std::fs::{create_dir_all, File};
+use std::path::{Component, Path, PathBuf};
 
-pub fn mkdirp<E>(path: E) -> Result<(), failure::Error>
+use sha2::{Digest, Sha256};
+
+use crate::error::{Error, IoContext};
+
+pub fn mkdirp<E>(path: E) -> Result<(), Error>
 where
     E: Into<OsString>,
 {
     let p = path.into();
     if !Path::new(&p).exists() {
-        create_dir_all(&p)?;
+        create_dir_all(&p).at(&p)?;
     }
     Ok(())
 }
 
+pub fn sha256sum<E>(path: E) -> Result<String, Error>
+where
+    E: Into<OsString>,
+{
+    let p = path.into();
+    let mut file = File::open(&p).at(&p)?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher).at(&p)?;
+    Ok(format!("{:x}", hasher.result()))
+}
+
+/// Where absolute `path` ends up when installing under `root`, like
+/// `DESTDIR`: `/etc/foo` becomes `<root>/etc/foo`. `..` can't climb out of
+/// `root`. Relative paths are unchanged, and paths already under `root`
+/// only have their `..` resolved.
+pub fn rooted<R, P>(root: R, path: P) -> PathBuf
+where
+    R: AsRef<Path>,
+    P: AsRef<Path>,
+{
+    let path = path.as_ref();
+    if !path.has_root() {
+        return path.to_path_buf();
+    }
+    let (root, under) = (normalized(root.as_ref()), normalized(path));
+    match under.strip_prefix(&root) {
+        Ok(rel) => root.join(rel),
+        Err(_) => root.join(under.strip_prefix("/").unwrap_or(&under)),
+    }
+}
+
+/// `path` with `.` dropped and `..` applied, without touching the disk
+fn normalized(path: &Path) -> PathBuf {
+    let mut normal = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::ParentDir => {
+                normal.pop();
+            }
+            Component::CurDir => {}
+            component => normal.push(component),
+        }
+    }
+    normal
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -21,4 +71,35 @@ mod tests {
     fn test_basename() {
         assert_eq!(Path::new("foo/bar/can.txt").file_name().unwrap(), "can.txt")
     }
+
+    #[test]
+    fn test_rooted() {
+        let root = Path::new("/tmp/stage");
+        assert_eq!(rooted(root, "/etc/foo"), Path::new("/tmp/stage/etc/foo"));
+        assert_eq!(rooted(root, "/../../etc"), Path::new("/tmp/stage/etc"));
+        assert_eq!(rooted(root, "/tmp/stage/bin"), Path::new("/tmp/stage/bin"));
+        assert_eq!(
+            rooted(root, "/tmp/stage/../../etc/passwd"),
+            Path::new("/tmp/stage/etc/passwd")
+        );
+        assert_eq!(
+            rooted(root, "/tmp/stage/lib/../bin"),
+            Path::new("/tmp/stage/bin")
+        );
+        assert_eq!(rooted(root, "etc/foo"), Path::new("etc/foo"));
+    }
+
+    #[test]
+    fn test_sha256sum() {
+        let tmp_dir = tempfile::Builder::new()
+            .prefix(env!("CARGO_PKG_NAME"))
+            .tempdir()
+            .unwrap();
+        let path = tmp_dir.path().join("abc.txt");
+        std::fs::write(&path, "abc").unwrap();
+        assert_eq!(
+            sha256sum(&path).unwrap(),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
 }
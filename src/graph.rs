@@ -0,0 +1,409 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::thread;
+
+use crate::plan::Plan;
+use crate::runner::{Outcome, Runner};
+use crate::{Context, Error, OffRegisters};
+
+/// Registers that (indirectly) depend on themselves
+#[derive(Debug)]
+pub struct CycleError {
+    /// Each depends on the next, and the last on the first
+    pub cycle: Vec<String>,
+}
+
+impl fmt::Display for CycleError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "dependency cycle: {}", self.cycle.join(" -> "))
+    }
+}
+
+impl std::error::Error for CycleError {}
+
+#[derive(Debug)]
+pub struct UnknownDependencyError {
+    pub register: String,
+    pub dependency: String,
+}
+
+impl fmt::Display for UnknownDependencyError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:?} depends on {:?}, which isn't one of the registers",
+            self.register, self.dependency
+        )
+    }
+}
+
+impl std::error::Error for UnknownDependencyError {}
+
+/// `registers` in waves: each only depends on registers in earlier waves, so
+/// the members of a wave are independent of each other. Sorted by name
+/// within a wave.
+pub fn install_order<'a>(
+    registers: &[&'a dyn OffRegisters],
+) -> Result<Vec<Vec<&'a dyn OffRegisters>>, Error> {
+    let mut by_name: BTreeMap<&'a str, &'a dyn OffRegisters> = BTreeMap::new();
+    for register in registers {
+        if by_name.insert(register.name(), *register).is_some() {
+            return Err(Error::DuplicateRegister(register.name().to_string()));
+        }
+    }
+
+    let mut remaining: BTreeMap<&'a str, Vec<&'a str>> = BTreeMap::new();
+    for (name, register) in &by_name {
+        let dependencies = register.dependencies();
+        if let Some(missing) = dependencies.iter().find(|d| !by_name.contains_key(*d)) {
+            return Err(UnknownDependencyError {
+                register: name.to_string(),
+                dependency: missing.to_string(),
+            }
+            .into());
+        }
+        remaining.insert(name, dependencies);
+    }
+
+    let mut placed: BTreeSet<&str> = BTreeSet::new();
+    let mut waves = Vec::new();
+    while !remaining.is_empty() {
+        let ready: Vec<&'a str> = remaining
+            .iter()
+            .filter(|(_, deps)| deps.iter().all(|d| placed.contains(d)))
+            .map(|(name, _)| *name)
+            .collect();
+        if ready.is_empty() {
+            return Err(CycleError {
+                cycle: find_cycle(&remaining),
+            }
+            .into());
+        }
+        for name in &ready {
+            remaining.remove(name);
+            placed.insert(name);
+        }
+        waves.push(ready.iter().map(|name| by_name[name]).collect());
+    }
+    Ok(waves)
+}
+
+// Everything left has an unplaced dependency, so following those must loop
+fn find_cycle(remaining: &BTreeMap<&str, Vec<&str>>) -> Vec<String> {
+    let mut path: Vec<&str> = Vec::new();
+    let mut name = match remaining.keys().next() {
+        Some(name) => *name,
+        None => return Vec::new(),
+    };
+    while !path.contains(&name) {
+        path.push(name);
+        name = remaining[name]
+            .iter()
+            .find(|d| remaining.contains_key(*d))
+            .copied()
+            .unwrap_or(name);
+    }
+    let start = path.iter().position(|n| *n == name).unwrap_or(0);
+    path[start..].iter().map(|n| n.to_string()).collect()
+}
+
+/// Installs a set of registers in dependency order, and uninstalls them in
+/// reverse. A register whose dependency failed is reported as `Blocked`
+/// rather than run; registers on independent branches carry on.
+#[derive(Default)]
+pub struct Orchestrator {
+    pub runner: Runner,
+    /// Run the registers of each wave concurrently. They share the process
+    /// environment, so should read and set it through `Context::env_var`
+    /// and `Context::set_env`.
+    pub parallel: bool,
+}
+
+impl Orchestrator {
+    pub fn install(
+        &self,
+        registers: &[&dyn OffRegisters],
+        ctx: &Context,
+    ) -> Result<Vec<(String, Outcome)>, Error> {
+        let waves = install_order(registers)?;
+        Ok(self.run_waves(
+            waves,
+            ctx,
+            |register, ok| {
+                register
+                    .dependencies()
+                    .into_iter()
+                    .find(|d| !ok.contains(d))
+                    .map(String::from)
+            },
+            |register| self.runner.install(register, ctx),
+        ))
+    }
+
+    pub fn uninstall(
+        &self,
+        registers: &[&dyn OffRegisters],
+        ctx: &Context,
+    ) -> Result<Vec<(String, Outcome)>, Error> {
+        let mut waves = install_order(registers)?;
+        waves.reverse();
+        let mut dependents: BTreeMap<&str, Vec<&str>> = BTreeMap::new();
+        for register in registers {
+            for dependency in register.dependencies() {
+                dependents
+                    .entry(dependency)
+                    .or_default()
+                    .push(register.name());
+            }
+        }
+        Ok(self.run_waves(
+            waves,
+            ctx,
+            |register, ok| {
+                dependents
+                    .get(register.name())
+                    .and_then(|names| names.iter().find(|d| !ok.contains(*d)))
+                    .map(|d| d.to_string())
+            },
+            |register| self.runner.uninstall(register, ctx),
+        ))
+    }
+
+    /// Verifies every register, in install order. Unlike the other phases
+    /// nothing is skipped when a dependency turns out to be unhealthy.
+    pub fn verify(
+        &self,
+        registers: &[&dyn OffRegisters],
+        ctx: &Context,
+    ) -> Result<Vec<(String, Outcome)>, Error> {
+        let waves = install_order(registers)?;
+        Ok(self.run_waves(
+            waves,
+            ctx,
+            |_, _| None,
+            |register| self.runner.verify(register, ctx),
+        ))
+    }
+
+    /// Repairs every register, in install order, skipping those whose
+    /// dependencies couldn't be repaired
+    pub fn repair(
+        &self,
+        registers: &[&dyn OffRegisters],
+        ctx: &Context,
+    ) -> Result<Vec<(String, Outcome)>, Error> {
+        let waves = install_order(registers)?;
+        Ok(self.run_waves(
+            waves,
+            ctx,
+            |register, ok| {
+                register
+                    .dependencies()
+                    .into_iter()
+                    .find(|d| !ok.contains(d))
+                    .map(String::from)
+            },
+            |register| self.runner.repair(register, ctx),
+        ))
+    }
+
+    /// Dry runs every register, in install order. Only what registers do
+    /// through the `Context` helpers is held back; see `Runner::plan`.
+    pub fn plan(&self, registers: &[&dyn OffRegisters], ctx: &Context) -> Result<Plan, Error> {
+        let mut plan = Plan::default();
+        for register in install_order(registers)?.into_iter().flatten() {
+            plan.registers.push(self.runner.plan(register, ctx)?);
+        }
+        Ok(plan)
+    }
+
+    /// `blocker` names a register that had to succeed before this one can run
+    fn run_waves<'a, B, R>(
+        &self,
+        waves: Vec<Vec<&'a dyn OffRegisters>>,
+        ctx: &Context,
+        blocker: B,
+        run: R,
+    ) -> Vec<(String, Outcome)>
+    where
+        B: Fn(&dyn OffRegisters, &BTreeSet<&str>) -> Option<String>,
+        R: Fn(&dyn OffRegisters) -> Outcome + Sync,
+    {
+        let mut ok: BTreeSet<&'a str> = BTreeSet::new();
+        let mut outcomes = Vec::new();
+        for wave in waves {
+            let (blocked, runnable): (Vec<_>, Vec<_>) = wave
+                .into_iter()
+                .map(|register| (register, blocker(register, &ok)))
+                .partition(|(_, blocker)| blocker.is_some());
+            for (register, blocker) in blocked {
+                let blocker = blocker.unwrap_or_default();
+                ctx.log(&format!("{}: skipped, {} failed", register.name(), blocker));
+                outcomes.push((register.name().to_string(), Outcome::Blocked(blocker)));
+            }
+
+            let runnable: Vec<&'a dyn OffRegisters> =
+                runnable.into_iter().map(|(r, _)| r).collect();
+            let results: Vec<Outcome> = if self.parallel && runnable.len() > 1 {
+                thread::scope(|s| {
+                    let handles: Vec<_> = runnable
+                        .iter()
+                        .map(|register| {
+                            let run = &run;
+                            s.spawn(move || run(*register))
+                        })
+                        .collect();
+                    handles
+                        .into_iter()
+                        .map(|h| h.join().unwrap_or_else(|e| std::panic::resume_unwind(e)))
+                        .collect()
+                })
+            } else {
+                runnable.iter().map(|register| run(*register)).collect()
+            };
+
+            for (register, outcome) in runnable.into_iter().zip(results) {
+                if outcome.is_ok() {
+                    ok.insert(register.name());
+                }
+                outcomes.push((register.name().to_string(), outcome));
+            }
+        }
+        outcomes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::{Arc, Mutex};
+
+    struct Node {
+        name: &'static str,
+        deps: Vec<&'static str>,
+        fail: bool,
+        log: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl OffRegisters for Node {
+        fn name(&self) -> &str {
+            self.name
+        }
+        fn dependencies(&self) -> Vec<&str> {
+            self.deps.clone()
+        }
+        fn already_setup(&self, _ctx: &Context) -> Result<bool, Error> {
+            Ok(false)
+        }
+        fn install(&self, _ctx: &Context) -> Result<(), Error> {
+            self.log.lock().unwrap().push(format!("+{}", self.name));
+            if self.fail {
+                return Err(Error::msg(format!("{} broke", self.name)));
+            }
+            Ok(())
+        }
+        fn uninstall(&self, _ctx: &Context) -> Result<(), Error> {
+            self.log.lock().unwrap().push(format!("-{}", self.name));
+            Ok(())
+        }
+    }
+
+    /// api -> postgres -> user, cache -> user
+    fn stack(log: &Arc<Mutex<Vec<String>>>, failing: &str) -> Vec<Node> {
+        let node = |name, deps| Node {
+            name,
+            deps,
+            fail: name == failing,
+            log: log.clone(),
+        };
+        vec![
+            node("api", vec!["postgres", "user"]),
+            node("cache", vec!["user"]),
+            node("postgres", vec!["user"]),
+            node("user", vec![]),
+        ]
+    }
+
+    fn refs(nodes: &[Node]) -> Vec<&dyn OffRegisters> {
+        nodes.iter().map(|n| n as &dyn OffRegisters).collect()
+    }
+
+    #[test]
+    fn test_install_order() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let nodes = stack(&log, "");
+        let waves: Vec<Vec<&str>> = install_order(&refs(&nodes))
+            .unwrap()
+            .iter()
+            .map(|wave| wave.iter().map(|r| r.name()).collect())
+            .collect();
+        assert_eq!(
+            waves,
+            vec![vec!["user"], vec!["cache", "postgres"], vec!["api"]]
+        );
+    }
+
+    #[test]
+    fn test_cycle_and_unknown() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let mut nodes = stack(&log, "");
+        nodes[3].deps = vec!["api"];
+        let err = install_order(&refs(&nodes)).err().unwrap();
+        match err {
+            Error::Cycle(e) => assert_eq!(e.cycle, vec!["api", "postgres", "user"]),
+            e => panic!("expected a cycle, got {}", e),
+        }
+
+        nodes[3].deps = vec!["systemd"];
+        let err = install_order(&refs(&nodes)).err().unwrap();
+        match err {
+            Error::UnknownDependency(e) => {
+                assert_eq!(e.register, "user");
+                assert_eq!(e.dependency, "systemd");
+            }
+            e => panic!("expected an unknown dependency, got {}", e),
+        }
+    }
+
+    #[test]
+    fn test_orchestrate() {
+        for parallel in [false, true].iter() {
+            let log = Arc::new(Mutex::new(Vec::new()));
+            let nodes = stack(&log, "");
+            let orchestrator = Orchestrator {
+                parallel: *parallel,
+                ..Orchestrator::default()
+            };
+            let ctx = Context::default();
+
+            let outcomes = orchestrator.install(&refs(&nodes), &ctx).unwrap();
+            assert!(outcomes.iter().all(|(_, o)| o.is_ok()));
+            let installed = log.lock().unwrap().clone();
+            assert_eq!(installed[0], "+user");
+            assert_eq!(installed[3], "+api");
+
+            log.lock().unwrap().clear();
+            orchestrator.uninstall(&refs(&nodes), &ctx).unwrap();
+            let uninstalled = log.lock().unwrap().clone();
+            assert_eq!(uninstalled[0], "-api");
+            assert_eq!(uninstalled[3], "-user");
+        }
+    }
+
+    #[test]
+    fn test_orchestrate_failure() {
+        let log = Arc::new(Mutex::new(Vec::new()));
+        let nodes = stack(&log, "postgres");
+        let outcomes = Orchestrator::default()
+            .install(&refs(&nodes), &Context::default())
+            .unwrap();
+        let outcome = |name| &outcomes.iter().find(|(n, _)| n == name).unwrap().1;
+
+        assert!(matches!(outcome("user"), Outcome::Installed));
+        assert!(matches!(outcome("cache"), Outcome::Installed));
+        assert!(matches!(outcome("postgres"), Outcome::Failed(_)));
+        assert!(matches!(outcome("api"), Outcome::Blocked(ref b) if b == "postgres"));
+        assert!(!log.lock().unwrap().contains(&"+api".to_string()));
+    }
+}
@@ -1,18 +1,180 @@
-#[macro_use]
-extern crate failure;
-
-#[macro_use]
+#[cfg_attr(test, macro_use)]
 extern crate lazy_static;
 
-pub trait OffRegisters {
-    fn already_setup() -> Result<bool, failure::Error>;
-    fn pre_install() -> Result<(), failure::Error>;
-    fn install() -> Result<(), failure::Error>;
-    fn post_install() -> Result<(), failure::Error>;
-    fn uninstall() -> Result<(), failure::Error>;
+use std::cmp::Ordering;
+use std::marker::PhantomData;
+
+pub use crate::context::Context;
+pub use crate::error::Error;
+
+/// One installable thing, e.g. PostgreSQL or a system user. Configuration
+/// lives on `self`, so registers can be kept as `Box<dyn OffRegisters>`.
+/// They are `Send + Sync` so independent ones can be installed in parallel.
+pub trait OffRegisters: Send + Sync {
+    fn name(&self) -> &str;
+    /// Names of the registers that have to be installed before this one
+    fn dependencies(&self) -> Vec<&str> {
+        Vec::new()
+    }
+    fn already_setup(&self, ctx: &Context) -> Result<bool, Error>;
+    /// The version this register installs, if it keeps track
+    fn version(&self) -> Option<&str> {
+        None
+    }
+    /// The version already installed, asked once `already_setup` is true
+    fn installed_version(&self, _ctx: &Context) -> Result<Option<String>, Error> {
+        Ok(None)
+    }
+    fn compare_versions(&self, a: &str, b: &str) -> Ordering {
+        version::compare(a, b)
+    }
+    fn pre_install(&self, _ctx: &Context) -> Result<(), Error> {
+        Ok(())
+    }
+    fn install(&self, ctx: &Context) -> Result<(), Error>;
+    fn post_install(&self, _ctx: &Context) -> Result<(), Error> {
+        Ok(())
+    }
+    fn uninstall(&self, ctx: &Context) -> Result<(), Error>;
+    /// Checks that an installation is healthy, beyond `already_setup`, with
+    /// the helpers in `check`. An error means the checks couldn't be run.
+    fn verify(&self, _ctx: &Context) -> Result<check::Report, Error> {
+        Ok(check::Report::default())
+    }
+    /// Replaces the `from` version that is installed with `version()`. By
+    /// default this uninstalls it and goes through the install phases again,
+    /// so a failure part way leaves neither version installed.
+    fn upgrade(&self, ctx: &Context, _from: &str) -> Result<(), Error> {
+        self.uninstall(ctx)?;
+        self.pre_install(ctx)?;
+        self.install(ctx)?;
+        self.post_install(ctx)
+    }
+}
+
+/// The original, stateless form of `OffRegisters`. Wrap implementors in
+/// `Static` to use them where an `OffRegisters` is expected.
+pub trait StaticOffRegisters {
+    fn already_setup() -> Result<bool, Error>;
+    fn pre_install() -> Result<(), Error>;
+    fn install() -> Result<(), Error>;
+    fn post_install() -> Result<(), Error>;
+    fn uninstall() -> Result<(), Error>;
+}
+
+pub struct Static<T> {
+    name: String,
+    register: PhantomData<fn() -> T>,
+}
+
+impl<T: StaticOffRegisters> Static<T> {
+    pub fn new<N: Into<String>>(name: N) -> Self {
+        Static {
+            name: name.into(),
+            register: PhantomData,
+        }
+    }
+}
+
+impl<T: StaticOffRegisters> OffRegisters for Static<T> {
+    fn name(&self) -> &str {
+        &self.name
+    }
+    fn already_setup(&self, _ctx: &Context) -> Result<bool, Error> {
+        T::already_setup()
+    }
+    fn pre_install(&self, _ctx: &Context) -> Result<(), Error> {
+        T::pre_install()
+    }
+    fn install(&self, _ctx: &Context) -> Result<(), Error> {
+        T::install()
+    }
+    fn post_install(&self, _ctx: &Context) -> Result<(), Error> {
+        T::post_install()
+    }
+    fn uninstall(&self, _ctx: &Context) -> Result<(), Error> {
+        T::uninstall()
+    }
 }
 
 pub mod archive;
+pub mod check;
+#[cfg(feature = "cli")]
+pub mod cli;
+pub mod config;
+pub mod context;
 pub mod download;
 pub mod env;
+pub mod error;
+pub mod event;
 pub mod fs;
+pub mod graph;
+pub mod manifest;
+pub mod package;
+pub mod plan;
+pub mod receipt;
+pub mod registry;
+pub mod runner;
+#[cfg(feature = "testing")]
+pub mod testing;
+pub mod version;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Legacy;
+
+    impl StaticOffRegisters for Legacy {
+        fn already_setup() -> Result<bool, Error> {
+            Ok(true)
+        }
+        fn pre_install() -> Result<(), Error> {
+            Ok(())
+        }
+        fn install() -> Result<(), Error> {
+            Err(Error::msg("legacy install"))
+        }
+        fn post_install() -> Result<(), Error> {
+            Ok(())
+        }
+        fn uninstall() -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    struct Versioned {
+        version: &'static str,
+    }
+
+    impl OffRegisters for Versioned {
+        fn name(&self) -> &str {
+            self.version
+        }
+        fn already_setup(&self, _ctx: &Context) -> Result<bool, Error> {
+            Ok(false)
+        }
+        fn install(&self, _ctx: &Context) -> Result<(), Error> {
+            Ok(())
+        }
+        fn uninstall(&self, _ctx: &Context) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_dyn_registers() {
+        let ctx = Context::default();
+        let registers: Vec<Box<dyn OffRegisters>> = vec![
+            Box::new(Static::<Legacy>::new("legacy")),
+            Box::new(Versioned { version: "12.1" }),
+        ];
+
+        assert_eq!(registers[0].name(), "legacy");
+        assert!(registers[0].already_setup(&ctx).unwrap());
+        assert!(registers[0].install(&ctx).is_err());
+        assert_eq!(registers[1].name(), "12.1");
+        assert!(!registers[1].already_setup(&ctx).unwrap());
+        assert!(registers[1].pre_install(&ctx).is_ok());
+    }
+}
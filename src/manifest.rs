@@ -0,0 +1,222 @@
+use std::collections::BTreeSet;
+use std::ffi::OsString;
+use std::fs::{remove_dir, remove_file, symlink_metadata, Metadata};
+use std::io::ErrorKind;
+use std::path::{Component, Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::{Error, IoContext};
+use crate::fs::{mkdirp, sha256sum};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EntryKind {
+    File,
+    Directory,
+    Symlink,
+    Other,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    /// Relative to `Manifest::root`
+    pub path: PathBuf,
+    pub kind: EntryKind,
+    pub size: u64,
+    pub mode: u32,
+    /// Hex encoded sha256, files only
+    pub digest: Option<String>,
+    /// Was already there before an extraction overwrote it, so not the
+    /// extraction's to remove
+    #[serde(default)]
+    pub replaced: bool,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub root: PathBuf,
+    pub entries: Vec<ManifestEntry>,
+}
+
+impl Manifest {
+    /// Stats each of `paths` (relative to `root`) as it is now on disk
+    pub fn from_paths<R>(
+        root: R,
+        paths: &BTreeSet<PathBuf>,
+        digest: bool,
+    ) -> Result<Manifest, Error>
+    where
+        R: Into<OsString>,
+    {
+        let root = PathBuf::from(root.into());
+        let mut entries = Vec::with_capacity(paths.len());
+        for rel in paths {
+            let path = root.join(rel);
+            let metadata = symlink_metadata(&path).at(&path)?;
+            let kind = entry_kind(&metadata);
+            entries.push(ManifestEntry {
+                path: rel.clone(),
+                kind,
+                size: if kind == EntryKind::File {
+                    metadata.len()
+                } else {
+                    0
+                },
+                mode: mode(&metadata),
+                digest: if digest && kind == EntryKind::File {
+                    Some(sha256sum(&path)?)
+                } else {
+                    None
+                },
+                replaced: false,
+            });
+        }
+        Ok(Manifest { root, entries })
+    }
+
+    pub fn save<P>(&self, path: P) -> Result<(), Error>
+    where
+        P: Into<OsString>,
+    {
+        let p = path.into();
+        if let Some(parent) = Path::new(&p).parent() {
+            mkdirp(parent)?;
+        }
+        std::fs::write(&p, serde_json::to_vec_pretty(self)?).at(&p)?;
+        Ok(())
+    }
+
+    pub fn load<P>(path: P) -> Result<Manifest, Error>
+    where
+        P: Into<OsString>,
+    {
+        let p = path.into();
+        Ok(serde_json::from_slice(&std::fs::read(&p).at(&p)?)?)
+    }
+
+    /// Deletes every entry, deepest first, except those that were
+    /// `replaced`. Directories are left in place if something not in the
+    /// manifest has since been put in them.
+    pub fn remove(&self) -> Result<(), Error> {
+        let mut entries: Vec<&ManifestEntry> =
+            self.entries.iter().filter(|e| !e.replaced).collect();
+        entries.sort_by(|a, b| b.path.cmp(&a.path));
+        for entry in entries {
+            if !is_relative_normal(&entry.path) {
+                return Err(Error::invalid_path(
+                    &entry.path,
+                    format!("refusing to remove outside of {}", self.root.display()),
+                ));
+            }
+            let path = self.root.join(&entry.path);
+            let result = match entry.kind {
+                EntryKind::Directory if !is_empty_dir(&path) => continue,
+                EntryKind::Directory => remove_dir(&path),
+                _ => remove_file(&path),
+            };
+            match result {
+                Err(ref e) if e.kind() == ErrorKind::NotFound => {}
+                r => r.at(&path)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+pub(crate) fn is_empty_dir(path: &Path) -> bool {
+    match path.read_dir() {
+        Ok(mut dir) => dir.next().is_none(),
+        Err(_) => true,
+    }
+}
+
+pub(crate) fn is_relative_normal(path: &Path) -> bool {
+    path.components().all(|c| matches!(c, Component::Normal(_)))
+}
+
+fn entry_kind(metadata: &Metadata) -> EntryKind {
+    let file_type = metadata.file_type();
+    if file_type.is_symlink() {
+        EntryKind::Symlink
+    } else if file_type.is_dir() {
+        EntryKind::Directory
+    } else if file_type.is_file() {
+        EntryKind::File
+    } else {
+        EntryKind::Other
+    }
+}
+
+#[cfg(unix)]
+fn mode(metadata: &Metadata) -> u32 {
+    use std::os::unix::fs::PermissionsExt;
+    metadata.permissions().mode() & 0o7777
+}
+
+#[cfg(not(unix))]
+fn mode(metadata: &Metadata) -> u32 {
+    if metadata.permissions().readonly() {
+        0o444
+    } else {
+        0o644
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::TempDir;
+
+    fn fixture() -> (TempDir, Manifest) {
+        let tmp_dir: TempDir = tempfile::Builder::new()
+            .prefix(env!("CARGO_PKG_NAME"))
+            .tempdir()
+            .unwrap();
+        mkdirp(tmp_dir.path().join("bin")).unwrap();
+        std::fs::write(tmp_dir.path().join("bin").join("tool"), "tool").unwrap();
+
+        let paths: BTreeSet<PathBuf> = vec![PathBuf::from("bin"), PathBuf::from("bin/tool")]
+            .into_iter()
+            .collect();
+        let manifest = Manifest::from_paths(tmp_dir.path(), &paths, true).unwrap();
+        (tmp_dir, manifest)
+    }
+
+    #[test]
+    fn test_from_paths() {
+        let (_tmp_dir, manifest) = fixture();
+        assert_eq!(manifest.entries.len(), 2);
+        assert_eq!(manifest.entries[0].kind, EntryKind::Directory);
+        assert_eq!(manifest.entries[0].digest, None);
+        assert_eq!(manifest.entries[1].kind, EntryKind::File);
+        assert_eq!(manifest.entries[1].size, 4);
+        assert!(manifest.entries[1].digest.is_some());
+    }
+
+    #[test]
+    fn test_save_load() {
+        let (tmp_dir, manifest) = fixture();
+        let manifest_path = tmp_dir.path().join("state").join("manifest.json");
+        manifest.save(&manifest_path).unwrap();
+        assert_eq!(Manifest::load(&manifest_path).unwrap(), manifest);
+    }
+
+    #[test]
+    fn test_remove() {
+        let (tmp_dir, manifest) = fixture();
+        manifest.remove().unwrap();
+        assert!(!tmp_dir.path().join("bin").exists());
+        assert!(tmp_dir.path().exists());
+    }
+
+    #[test]
+    fn test_remove_keeps_foreign_files() {
+        let (tmp_dir, manifest) = fixture();
+        std::fs::write(tmp_dir.path().join("bin").join("mine"), "mine").unwrap();
+        manifest.remove().unwrap();
+        assert!(!tmp_dir.path().join("bin").join("tool").exists());
+        assert!(tmp_dir.path().join("bin").join("mine").exists());
+    }
+}
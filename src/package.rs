@@ -0,0 +1,769 @@
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::ffi::{OsStr, OsString};
+use std::fs::File;
+use std::io::{self, BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use serde::{Deserialize, Serialize};
+use tar::{Archive, EntryType, Header};
+
+use crate::archive::{
+    decompressing, extracted, limit_error, unpack, untar_reader, ExtractLimitError, ExtractLimits,
+    ExtractOptions,
+};
+use crate::error::{Error, IoContext};
+use crate::fs::mkdirp;
+use crate::manifest::Manifest;
+
+/// Metadata of a `.deb` or `.rpm`
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PackageInfo {
+    pub name: String,
+    /// `Version` for a deb, `<version>-<release>` for an rpm
+    pub version: String,
+    pub arch: String,
+    /// Every control field of a deb, or the rpm header tags we know of,
+    /// keyed by their lowercase name
+    pub fields: BTreeMap<String, String>,
+}
+
+/// Extracts the `data.tar.*` payload of a Debian package
+pub fn extract_deb<D, E>(
+    deb: D,
+    extract_dir: Option<E>,
+    options: &ExtractOptions,
+) -> Result<Manifest, Error>
+where
+    D: Into<OsString>,
+    E: Into<OsString>,
+{
+    let deb = deb.into();
+    let extract_to = prepare_extract_dir(extract_dir)?;
+    let mut ar = ar::Archive::new(File::open(&deb).at(&deb)?);
+    while let Some(entry) = ar.next_entry() {
+        let entry = entry.at(&deb)?;
+        if ar_name(entry.header()).starts_with("data.tar") {
+            let manifest = untar_reader(entry, Path::new(&extract_to), options)?;
+            return Ok(extracted(&deb.to_string_lossy(), manifest, options));
+        }
+    }
+    Err(Error::archive(&deb, "no data.tar member found"))
+}
+
+/// The fields of the `control` file in a Debian package
+pub fn deb_info<D>(deb: D) -> Result<PackageInfo, Error>
+where
+    D: Into<OsString>,
+{
+    let deb = deb.into();
+    let mut ar = ar::Archive::new(File::open(&deb).at(&deb)?);
+    while let Some(entry) = ar.next_entry() {
+        let entry = entry.at(&deb)?;
+        if !ar_name(entry.header()).starts_with("control.tar") {
+            continue;
+        }
+        let mut control = Archive::new(decompressing(entry, &ExtractLimits::default())?);
+        for file in control.entries().at(&deb)? {
+            let mut file = file.at(&deb)?;
+            if file
+                .path()
+                .at(&deb)?
+                .file_name()
+                .is_some_and(|n| n == "control")
+            {
+                let mut text = String::new();
+                file.read_to_string(&mut text).at(&deb)?;
+                let fields = parse_control(&text);
+                let field = |key: &str| fields.get(key).cloned().unwrap_or_default();
+                return Ok(PackageInfo {
+                    name: field("package"),
+                    version: field("version"),
+                    arch: field("architecture"),
+                    fields,
+                });
+            }
+        }
+    }
+    Err(Error::archive(&deb, "no control file found"))
+}
+
+fn ar_name(header: &ar::Header) -> String {
+    // GNU ar terminates names with a `/`
+    String::from_utf8_lossy(header.identifier())
+        .trim_end_matches('/')
+        .to_string()
+}
+
+/// `Key: value` lines, with indented continuation lines joined by newlines
+fn parse_control(text: &str) -> BTreeMap<String, String> {
+    let mut fields: BTreeMap<String, String> = BTreeMap::new();
+    let mut last: Option<String> = None;
+    for line in text.lines() {
+        if line.starts_with(' ') || line.starts_with('\t') {
+            if let Some(value) = last.as_ref().and_then(|key| fields.get_mut(key)) {
+                // A lone `.` stands for a blank line
+                value.push('\n');
+                value.push_str(line.trim().trim_start_matches('.'));
+            }
+        } else if let Some((key, value)) = line.split_once(':') {
+            let key = key.trim().to_lowercase();
+            fields.insert(key.clone(), value.trim().to_string());
+            last = Some(key);
+        }
+    }
+    fields
+}
+
+/// Extracts the cpio payload of an RPM package
+pub fn extract_rpm<D, E>(
+    rpm: D,
+    extract_dir: Option<E>,
+    options: &ExtractOptions,
+) -> Result<Manifest, Error>
+where
+    D: Into<OsString>,
+    E: Into<OsString>,
+{
+    let rpm = rpm.into();
+    let extract_to = prepare_extract_dir(extract_dir)?;
+    let mut reader = BufReader::new(File::open(&rpm).at(&rpm)?);
+    read_rpm_headers(&mut reader).map_err(|e| in_rpm(e, &rpm))?;
+
+    let payload = decompressing(reader, &options.limits)?;
+    let tripped = payload.tripped.clone();
+    let cpio = CpioToTar::new(payload, &options.limits);
+    let too_long = cpio.too_long.clone();
+    let manifest = unpack(&mut Archive::new(cpio), Path::new(&extract_to), options).map_err(
+        |e| match too_long.take() {
+            Some(limit) => limit.into(),
+            None => limit_error(&tripped, &options.limits, e),
+        },
+    )?;
+    Ok(extracted(&rpm.to_string_lossy(), manifest, options))
+}
+
+pub fn rpm_info<D>(rpm: D) -> Result<PackageInfo, Error>
+where
+    D: Into<OsString>,
+{
+    let rpm = rpm.into();
+    let fields = read_rpm_headers(&mut BufReader::new(File::open(&rpm).at(&rpm)?))
+        .map_err(|e| in_rpm(e, &rpm))?;
+    let field = |key: &str| fields.get(key).cloned().unwrap_or_default();
+    let version = match fields.get("release") {
+        Some(release) => format!("{}-{}", field("version"), release),
+        None => field("version"),
+    };
+    Ok(PackageInfo {
+        name: field("name"),
+        version,
+        arch: field("arch"),
+        fields,
+    })
+}
+
+fn prepare_extract_dir<E: Into<OsString>>(extract_dir: Option<E>) -> Result<OsString, Error> {
+    let extract_to = match extract_dir {
+        Some(d) => d.into(),
+        None => OsString::from("."),
+    };
+    mkdirp(&extract_to)?;
+    Ok(extract_to)
+}
+
+const RPM_LEAD_MAGIC: [u8; 4] = [0xed, 0xab, 0xee, 0xdb];
+const RPM_HEADER_MAGIC: [u8; 4] = [0x8e, 0xad, 0xe8, 0x01];
+
+const RPM_TAGS: &[(u32, &str)] = &[
+    (1000, "name"),
+    (1001, "version"),
+    (1002, "release"),
+    (1003, "epoch"),
+    (1004, "summary"),
+    (1005, "description"),
+    (1014, "license"),
+    (1020, "url"),
+    (1022, "arch"),
+    (1124, "payloadformat"),
+    (1125, "payloadcompressor"),
+];
+
+/// Skips the lead and signature, leaving `reader` at the start of the
+/// payload, and returns the main header's fields
+fn read_rpm_headers<R: Read>(reader: &mut R) -> Result<BTreeMap<String, String>, Error> {
+    let mut lead = [0; 96];
+    reader.read_exact(&mut lead)?;
+    if lead[..4] != RPM_LEAD_MAGIC {
+        return Err(rpm_error("bad lead magic"));
+    }
+
+    let signature = read_rpm_header(reader)?;
+    // The signature is padded to a multiple of 8 bytes
+    let padding = (8 - signature.len() % 8) % 8;
+    io::copy(&mut reader.take(padding as u64), &mut io::sink())?;
+
+    let header = read_rpm_header(reader)?;
+    let count = be_u32(&header[8..12]) as usize;
+    let index = &header[16..16 + count * 16];
+    let store = &header[16 + count * 16..];
+
+    let mut fields = BTreeMap::new();
+    for entry in index.chunks(16) {
+        let (tag, kind, offset) = (
+            be_u32(&entry[0..4]),
+            be_u32(&entry[4..8]),
+            be_u32(&entry[8..12]),
+        );
+        let name = match RPM_TAGS.iter().find(|(t, _)| *t == tag) {
+            Some((_, name)) => name,
+            None => continue,
+        };
+        let data = store
+            .get(offset as usize..)
+            .ok_or_else(|| rpm_error(format!("header tag {} out of bounds", tag)))?;
+        let value = match kind {
+            // INT32
+            4 if data.len() >= 4 => be_u32(&data[..4]).to_string(),
+            // STRING, STRING_ARRAY, I18NSTRING: the first string
+            6 | 8 | 9 => {
+                let end = data.iter().position(|b| *b == 0).unwrap_or(data.len());
+                String::from_utf8_lossy(&data[..end]).into_owned()
+            }
+            _ => continue,
+        };
+        fields.insert(name.to_string(), value);
+    }
+
+    match fields.get("payloadformat").map(String::as_str) {
+        None | Some("cpio") => Ok(fields),
+        Some(format) => Err(rpm_error(format!(
+            "unsupported payload format {:?}",
+            format
+        ))),
+    }
+}
+
+/// One header structure: magic, index and data store
+fn read_rpm_header<R: Read>(reader: &mut R) -> Result<Vec<u8>, Error> {
+    let mut header = vec![0; 16];
+    reader.read_exact(&mut header)?;
+    if header[..4] != RPM_HEADER_MAGIC {
+        return Err(rpm_error("bad header magic"));
+    }
+    let count = u64::from(be_u32(&header[8..12]));
+    let size = u64::from(be_u32(&header[12..16]));
+    let len = count * 16 + size;
+    reader.take(len).read_to_end(&mut header)?;
+    if header.len() as u64 != 16 + len {
+        return Err(rpm_error("truncated header"));
+    }
+    Ok(header)
+}
+
+fn rpm_error<R: Into<String>>(reason: R) -> Error {
+    Error::Archive {
+        path: None,
+        reason: format!("not a valid rpm: {}", reason.into()),
+    }
+}
+
+/// Adds the path of the package to errors from reading its headers
+fn in_rpm(error: Error, rpm: &OsStr) -> Error {
+    match error {
+        Error::Archive { path: None, reason } => Error::archive(rpm, reason),
+        Error::Io { path: None, source } => Error::Io {
+            path: Some(rpm.into()),
+            source,
+        },
+        error => error,
+    }
+}
+
+fn be_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+const CPIO_HEADER_LEN: usize = 110;
+const CPIO_TRAILER: &[u8] = b"TRAILER!!!";
+/// Longest symlink target, and name when `max_path_len` is unset
+const PATH_MAX: usize = 4096;
+
+const S_IFMT: u32 = 0o170_000;
+const S_IFREG: u32 = 0o100_000;
+const S_IFDIR: u32 = 0o040_000;
+const S_IFLNK: u32 = 0o120_000;
+
+/// Device major, device minor and inode number
+type Inode = (u32, u32, u32);
+
+/// Re-encodes a "newc" cpio stream as tar on the fly, so rpm payloads get the
+/// same limits, options and manifest as `untar`. Devices, fifos and sockets
+/// are dropped.
+struct CpioToTar<R> {
+    inner: R,
+    /// Encoded tar waiting to be read
+    out: Vec<u8>,
+    pos: usize,
+    /// Data of the current file still to be copied through
+    data_left: u64,
+    /// Padding after that data, in the cpio and tar streams respectively
+    cpio_pad: usize,
+    tar_pad: usize,
+    /// Hard linked files whose data hasn't appeared yet, by inode, with
+    /// the header to give them if it never does
+    links: BTreeMap<Inode, (Header, Vec<Vec<u8>>)>,
+    /// Links to emit once the current file's data is through
+    pending_links: Vec<(Vec<u8>, Vec<u8>)>,
+    /// Past the cpio trailer, flushing what is left in `links`
+    trailer: bool,
+    done: bool,
+    max_path_len: usize,
+    /// Set when a header asks for a name or link target that is too long,
+    /// which `tar` only sees as an I/O error
+    too_long: Rc<Cell<Option<ExtractLimitError>>>,
+}
+
+impl<R: Read> CpioToTar<R> {
+    fn new(inner: R, limits: &ExtractLimits) -> Self {
+        CpioToTar {
+            max_path_len: limits.max_path_len.unwrap_or(PATH_MAX),
+            too_long: Rc::new(Cell::new(None)),
+            inner,
+            out: Vec::new(),
+            pos: 0,
+            data_left: 0,
+            cpio_pad: 0,
+            tar_pad: 0,
+            links: BTreeMap::new(),
+            pending_links: Vec::new(),
+            trailer: false,
+            done: false,
+        }
+    }
+
+    /// Queues the next tar header(s); false at the end of the archive
+    fn next_entry(&mut self) -> io::Result<bool> {
+        let mut raw = [0; CPIO_HEADER_LEN];
+        self.inner.read_exact(&mut raw)?;
+        if &raw[..6] != b"070701" && &raw[..6] != b"070702" {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "not a newc cpio archive",
+            ));
+        }
+        let field = |i: usize| -> io::Result<u32> {
+            std::str::from_utf8(&raw[6 + i * 8..14 + i * 8])
+                .ok()
+                .and_then(|s| u32::from_str_radix(s, 16).ok())
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "bad cpio header"))
+        };
+        let (ino, mode, uid, gid, nlink, mtime) = (
+            field(0)?,
+            field(1)?,
+            field(2)?,
+            field(3)?,
+            field(4)?,
+            field(5)?,
+        );
+        let (size, dev) = (u64::from(field(6)?), (field(7)?, field(8)?));
+        let name_len = field(11)? as usize;
+
+        // Checked before allocating, since the header can claim up to 4 GiB
+        if name_len > self.max_path_len + 1 {
+            let mut start = vec![0; self.max_path_len];
+            self.inner.read_exact(&mut start)?;
+            return Err(self.too_long(&start));
+        }
+        let mut name = vec![0; name_len];
+        self.inner.read_exact(&mut name)?;
+        skip(&mut self.inner, pad4(CPIO_HEADER_LEN + name_len))?;
+        name.pop(); // NUL
+        if name == CPIO_TRAILER {
+            return Ok(false);
+        }
+
+        let mut header = Header::new_gnu();
+        header.set_mode(mode & 0o7777);
+        header.set_uid(u64::from(uid));
+        header.set_gid(u64::from(gid));
+        header.set_mtime(u64::from(mtime));
+        header.set_size(0);
+
+        match mode & S_IFMT {
+            S_IFDIR => header.set_entry_type(EntryType::Directory),
+            S_IFLNK => {
+                if size > PATH_MAX as u64 {
+                    return Err(self.too_long(&name));
+                }
+                let mut target = vec![0; size as usize];
+                self.inner.read_exact(&mut target)?;
+                skip(&mut self.inner, pad4(size as usize))?;
+                header.set_entry_type(EntryType::Symlink);
+                self.push_long(EntryType::GNULongLink, &target, &mut header, false);
+            }
+            S_IFREG if nlink > 1 && size == 0 => {
+                // newc only stores a hard linked file's data with its last name
+                header.set_entry_type(EntryType::Regular);
+                self.links
+                    .entry((dev.0, dev.1, ino))
+                    .or_insert_with(|| (header, Vec::new()))
+                    .1
+                    .push(name);
+                return Ok(true);
+            }
+            S_IFREG => {
+                header.set_entry_type(EntryType::Regular);
+                header.set_size(size);
+                self.data_left = size;
+                self.cpio_pad = pad4(size as usize);
+                self.tar_pad = pad512(size as usize);
+                if nlink > 1 {
+                    let links = self.links.remove(&(dev.0, dev.1, ino));
+                    for link in links.map(|(_, names)| names).unwrap_or_default() {
+                        self.pending_links.push((link, name.clone()));
+                    }
+                }
+            }
+            _ => {
+                skip(&mut self.inner, size as usize + pad4(size as usize))?;
+                return Ok(true);
+            }
+        }
+        self.push_long(EntryType::GNULongName, &name, &mut header, true);
+        header.set_cksum();
+        self.out.extend_from_slice(header.as_bytes());
+        Ok(true)
+    }
+
+    fn too_long(&self, name: &[u8]) -> io::Error {
+        let path = PathBuf::from(String::from_utf8_lossy(name).into_owned());
+        self.too_long.set(Some(ExtractLimitError::PathLength(path)));
+        io::Error::new(io::ErrorKind::InvalidData, "cpio path too long")
+    }
+
+    /// Puts `value` in the header's name or link name field, preceded by a
+    /// GNU long name entry when it doesn't fit
+    fn push_long(&mut self, kind: EntryType, value: &[u8], header: &mut Header, is_name: bool) {
+        let field = if is_name {
+            &mut header.as_old_mut().name
+        } else {
+            &mut header.as_old_mut().linkname
+        };
+        let len = value.len().min(field.len());
+        field[..len].copy_from_slice(&value[..len]);
+        if value.len() <= field.len() {
+            return;
+        }
+
+        let mut long = Header::new_gnu();
+        long.as_old_mut().name[..13].copy_from_slice(b"././@LongLink");
+        long.set_mode(0o644);
+        long.set_entry_type(kind);
+        long.set_size(value.len() as u64 + 1);
+        long.set_cksum();
+        self.out.extend_from_slice(long.as_bytes());
+        self.out.extend_from_slice(value);
+        self.out.push(0);
+        self.out.resize(self.out.len() + pad512(value.len() + 1), 0);
+    }
+
+    fn fill(&mut self) -> io::Result<()> {
+        self.out.clear();
+        self.pos = 0;
+        while self.out.is_empty() && !self.done {
+            if let Some((link, target)) = self.pending_links.pop() {
+                let mut header = Header::new_gnu();
+                header.set_entry_type(EntryType::Link);
+                header.set_mode(0o644);
+                header.set_size(0);
+                self.push_long(EntryType::GNULongLink, &target, &mut header, false);
+                self.push_long(EntryType::GNULongName, &link, &mut header, true);
+                header.set_cksum();
+                self.out.extend_from_slice(header.as_bytes());
+            } else if self.trailer || !self.next_entry()? {
+                self.trailer = true;
+                // Files that are empty under every name never got their data
+                if let Some((_, (mut header, names))) = self.links.pop_first() {
+                    let (first, links) = names.split_first().unwrap();
+                    for link in links {
+                        self.pending_links.push((link.clone(), first.clone()));
+                    }
+                    self.push_long(EntryType::GNULongName, first, &mut header, true);
+                    header.set_cksum();
+                    self.out.extend_from_slice(header.as_bytes());
+                } else {
+                    // Two zero blocks end a tar
+                    self.out.resize(1024, 0);
+                    self.done = true;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for CpioToTar<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos < self.out.len() {
+            let n = (self.out.len() - self.pos).min(buf.len());
+            buf[..n].copy_from_slice(&self.out[self.pos..self.pos + n]);
+            self.pos += n;
+            return Ok(n);
+        }
+        if self.data_left > 0 {
+            let max = buf.len().min(self.data_left as usize);
+            let n = self.inner.read(&mut buf[..max])?;
+            if n == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+            self.data_left -= n as u64;
+            return Ok(n);
+        }
+        if self.cpio_pad > 0 || self.tar_pad > 0 {
+            skip(&mut self.inner, self.cpio_pad)?;
+            self.out.clear();
+            self.out.resize(self.tar_pad, 0);
+            self.pos = 0;
+            self.cpio_pad = 0;
+            self.tar_pad = 0;
+            return self.read(buf);
+        }
+        if self.done {
+            return Ok(0);
+        }
+        self.fill()?;
+        self.read(buf)
+    }
+}
+
+fn skip<R: Read>(reader: &mut R, n: usize) -> io::Result<()> {
+    let skipped = io::copy(&mut reader.take(n as u64), &mut io::sink())?;
+    if skipped < n as u64 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+}
+
+fn pad4(len: usize) -> usize {
+    (4 - len % 4) % 4
+}
+
+fn pad512(len: usize) -> usize {
+    (512 - len % 512) % 512
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::io::Write;
+
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use tempfile::TempDir;
+
+    use crate::manifest::EntryKind;
+
+    fn tar_gz(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+        for (path, data) in files {
+            let mut header = Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o755);
+            header.set_cksum();
+            builder.append_data(&mut header, path, *data).unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    fn deb(path: &Path) {
+        let control = "Package: tool\nVersion: 1.2-3\nArchitecture: amd64\n\
+                       Description: a tool\n for testing\n .\n really\n";
+        let mut builder = ar::Builder::new(File::create(path).unwrap());
+        for (name, data) in [
+            ("debian-binary", b"2.0\n".to_vec()),
+            (
+                "control.tar.gz",
+                tar_gz(&[("./control", control.as_bytes())]),
+            ),
+            ("data.tar.gz", tar_gz(&[("./usr/bin/tool", b"tool")])),
+        ] {
+            let header = ar::Header::new(name.as_bytes().to_vec(), data.len() as u64);
+            builder.append(&header, &data[..]).unwrap();
+        }
+    }
+
+    fn cpio_entry(cpio: &mut Vec<u8>, name: &str, ino: u32, mode: u32, nlink: u32, data: &[u8]) {
+        let fields = [
+            ino,
+            mode,
+            0,
+            0,
+            nlink,
+            1_000_000,
+            data.len() as u32,
+            0,
+            0,
+            0,
+            0,
+        ];
+        cpio.extend_from_slice(b"070701");
+        for field in fields.iter() {
+            cpio.extend_from_slice(format!("{:08x}", field).as_bytes());
+        }
+        cpio.extend_from_slice(format!("{:08x}{:08x}", name.len() + 1, 0).as_bytes());
+        cpio.extend_from_slice(name.as_bytes());
+        cpio.push(0);
+        cpio.resize(cpio.len() + pad4(cpio.len()), 0);
+        cpio.extend_from_slice(data);
+        cpio.resize(cpio.len() + pad4(cpio.len()), 0);
+    }
+
+    fn rpm_header(tags: &[(u32, &str)]) -> Vec<u8> {
+        let mut index = Vec::new();
+        let mut store = Vec::new();
+        for (tag, value) in tags {
+            for n in [*tag, 6, store.len() as u32, 1].iter() {
+                index.extend_from_slice(&n.to_be_bytes());
+            }
+            store.extend_from_slice(value.as_bytes());
+            store.push(0);
+        }
+        let mut header = RPM_HEADER_MAGIC.to_vec();
+        header.extend_from_slice(&[0; 4]);
+        header.extend_from_slice(&(tags.len() as u32).to_be_bytes());
+        header.extend_from_slice(&(store.len() as u32).to_be_bytes());
+        header.extend(index);
+        header.extend(store);
+        header
+    }
+
+    fn rpm(path: &Path, long_name: &str) {
+        let mut cpio = Vec::new();
+        cpio_entry(&mut cpio, "./usr", 1, S_IFDIR | 0o755, 2, b"");
+        cpio_entry(&mut cpio, "./usr/bin", 2, S_IFDIR | 0o755, 2, b"");
+        cpio_entry(&mut cpio, "./usr/bin/tool", 3, S_IFREG | 0o755, 1, b"tool");
+        cpio_entry(&mut cpio, "./usr/bin/t", 4, S_IFLNK | 0o777, 1, b"tool");
+        cpio_entry(&mut cpio, "./usr/bin/a", 5, S_IFREG | 0o644, 2, b"");
+        cpio_entry(&mut cpio, "./usr/bin/b", 5, S_IFREG | 0o644, 2, b"linked");
+        cpio_entry(&mut cpio, long_name, 6, S_IFREG | 0o644, 1, b"long");
+        cpio_entry(&mut cpio, "./usr/bin/c", 7, S_IFREG | 0o644, 2, b"");
+        cpio_entry(&mut cpio, "./usr/bin/d", 7, S_IFREG | 0o644, 2, b"");
+        cpio_entry(&mut cpio, "TRAILER!!!", 0, 0, 1, b"");
+        write_rpm(path, &cpio);
+    }
+
+    fn write_rpm(path: &Path, cpio: &[u8]) {
+        let mut payload = GzEncoder::new(Vec::new(), Compression::default());
+        payload.write_all(cpio).unwrap();
+
+        let mut file = File::create(path).unwrap();
+        let mut lead = RPM_LEAD_MAGIC.to_vec();
+        lead.resize(96, 0);
+        file.write_all(&lead).unwrap();
+        // 16 bytes of signature with a 1 byte store, padded to 24
+        let mut signature = rpm_header(&[]);
+        signature[15] = 1;
+        signature.extend_from_slice(&[0; 8]);
+        file.write_all(&signature).unwrap();
+        let tags = [(1000, "tool"), (1001, "1.2"), (1002, "3"), (1022, "x86_64")];
+        file.write_all(&rpm_header(&tags)).unwrap();
+        file.write_all(&payload.finish().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_deb() {
+        let tmp_dir: TempDir = tempfile::Builder::new()
+            .prefix(env!("CARGO_PKG_NAME"))
+            .tempdir()
+            .unwrap();
+        let package = tmp_dir.path().join("tool_1.2-3_amd64.deb");
+        deb(&package);
+
+        let info = deb_info(&package).unwrap();
+        assert_eq!(info.name, "tool");
+        assert_eq!(info.version, "1.2-3");
+        assert_eq!(info.arch, "amd64");
+        assert_eq!(info.fields["description"], "a tool\nfor testing\n\nreally");
+
+        let extract_dir = tmp_dir.path().join("root");
+        let manifest =
+            extract_deb(&package, Some(&extract_dir), &ExtractOptions::default()).unwrap();
+        assert_eq!(
+            std::fs::read(extract_dir.join("usr").join("bin").join("tool")).unwrap(),
+            b"tool"
+        );
+        assert_eq!(manifest.entries.len(), 3);
+    }
+
+    #[test]
+    fn test_rpm() {
+        let tmp_dir: TempDir = tempfile::Builder::new()
+            .prefix(env!("CARGO_PKG_NAME"))
+            .tempdir()
+            .unwrap();
+        let package = tmp_dir.path().join("tool-1.2-3.x86_64.rpm");
+        let long_name = format!("./usr/share/{}", "x".repeat(120));
+        rpm(&package, &long_name);
+
+        let info = rpm_info(&package).unwrap();
+        assert_eq!(info.name, "tool");
+        assert_eq!(info.version, "1.2-3");
+        assert_eq!(info.arch, "x86_64");
+
+        let extract_dir = tmp_dir.path().join("root");
+        let manifest =
+            extract_rpm(&package, Some(&extract_dir), &ExtractOptions::default()).unwrap();
+        let bin = extract_dir.join("usr").join("bin");
+        assert_eq!(std::fs::read(bin.join("tool")).unwrap(), b"tool");
+        assert_eq!(
+            std::fs::read_link(bin.join("t")).unwrap(),
+            Path::new("tool")
+        );
+        assert_eq!(std::fs::read(bin.join("a")).unwrap(), b"linked");
+        assert_eq!(std::fs::read(bin.join("b")).unwrap(), b"linked");
+        assert_eq!(
+            std::fs::read(extract_dir.join(&long_name)).unwrap(),
+            b"long"
+        );
+        // Empty under both names, so never given data
+        assert_eq!(std::fs::read(bin.join("c")).unwrap(), b"");
+        assert_eq!(std::fs::read(bin.join("d")).unwrap(), b"");
+
+        let kinds: Vec<EntryKind> = manifest.entries.iter().map(|e| e.kind).collect();
+        assert_eq!(manifest.entries.len(), 10);
+        assert!(kinds.contains(&EntryKind::Symlink));
+    }
+
+    #[test]
+    fn test_rpm_oversized_header() {
+        let tmp_dir: TempDir = tempfile::Builder::new()
+            .prefix(env!("CARGO_PKG_NAME"))
+            .tempdir()
+            .unwrap();
+        // The name length (field 11) or symlink size (field 6) claims 4 GiB
+        for (field, mode) in [(11, S_IFREG | 0o644), (6, S_IFLNK | 0o777)] {
+            let mut cpio = Vec::new();
+            cpio_entry(&mut cpio, "./usr/bin/t", 1, mode, 1, b"tool");
+            let at = 6 + field * 8;
+            cpio[at..at + 8].copy_from_slice(b"ffffffff");
+            cpio.resize(cpio.len() + 8192, 0);
+            let package = tmp_dir.path().join(format!("bomb-{}.rpm", field));
+            write_rpm(&package, &cpio);
+
+            let err = extract_rpm(
+                &package,
+                Some(tmp_dir.path().join("root")),
+                &ExtractOptions::default(),
+            )
+            .unwrap_err();
+            assert!(
+                matches!(err, Error::Limit(ExtractLimitError::PathLength(_))),
+                "{}",
+                err
+            );
+        }
+    }
+}
@@ -0,0 +1,194 @@
+use std::fmt;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+
+/// Something a `Context` helper would have done, had this not been a dry run
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Action {
+    Download { url: String, to: PathBuf },
+    Extract { archive: String, to: PathBuf },
+    CreateDir { path: PathBuf },
+    WriteFile { path: PathBuf, size: u64 },
+    SetEnv { key: String, value: String },
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Action::Download { url, to } => write!(f, "download {} to {}", url, to.display()),
+            Action::Extract { archive, to } => {
+                write!(f, "extract {} into {}", archive, to.display())
+            }
+            Action::CreateDir { path } => write!(f, "create directory {}", path.display()),
+            Action::WriteFile { path, size } => {
+                write!(f, "write {} ({} bytes)", path.display(), size)
+            }
+            Action::SetEnv { key, value } => write!(f, "set {}={}", key, value),
+        }
+    }
+}
+
+/// Where a dry run's `Context` puts its actions
+#[derive(Debug, Default)]
+pub struct Recorder {
+    actions: Mutex<Vec<Action>>,
+}
+
+impl Recorder {
+    pub fn record(&self, action: Action) {
+        if let Ok(mut actions) = self.actions.lock() {
+            actions.push(action);
+        }
+    }
+
+    pub fn take(&self) -> Vec<Action> {
+        match self.actions.lock() {
+            Ok(mut actions) => std::mem::take(&mut *actions),
+            Err(_) => Vec::new(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Upgrade {
+    pub from: String,
+    pub to: String,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RegisterPlan {
+    pub name: String,
+    /// `already_setup` returned true, so nothing would be done unless
+    /// there's an `upgrade`
+    pub already_setup: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub upgrade: Option<Upgrade>,
+    pub actions: Vec<Action>,
+}
+
+/// What installing a set of registers would do, in order
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Plan {
+    pub registers: Vec<RegisterPlan>,
+}
+
+impl Plan {
+    pub fn to_json(&self) -> Result<String, Error> {
+        Ok(serde_json::to_string_pretty(self)?)
+    }
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for register in &self.registers {
+            match &register.upgrade {
+                Some(Upgrade { from, to }) => {
+                    writeln!(f, "{}: upgrade from {} to {}", register.name, from, to)?
+                }
+                None if register.already_setup => {
+                    writeln!(f, "{}: already set up", register.name)?;
+                    continue;
+                }
+                None => writeln!(f, "{}:", register.name)?,
+            }
+            for action in &register.actions {
+                writeln!(f, "  {}", action)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::TempDir;
+    use url::Url;
+
+    use crate::graph::Orchestrator;
+    use crate::{Context, OffRegisters};
+
+    struct Server {
+        prefix: PathBuf,
+    }
+
+    impl OffRegisters for Server {
+        fn name(&self) -> &str {
+            "server"
+        }
+        fn already_setup(&self, _ctx: &Context) -> Result<bool, Error> {
+            Ok(self.prefix.join("bin").exists())
+        }
+        fn install(&self, ctx: &Context) -> Result<(), Error> {
+            let url = Url::parse("https://example.com/server-1.0.tar.gz")?;
+            ctx.untar_url(&url, self.prefix.join("bin"), None)?;
+            ctx.write(self.prefix.join("etc").join("server.conf"), "port = 80\n")?;
+            ctx.set_env("OFFREGISTERS_PLAN_TEST", "1");
+            Ok(())
+        }
+        fn uninstall(&self, _ctx: &Context) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_plan() {
+        let tmp_dir: TempDir = tempfile::Builder::new()
+            .prefix(env!("CARGO_PKG_NAME"))
+            .tempdir()
+            .unwrap();
+        let prefix = tmp_dir.path().join("prefix");
+        let ctx = Context {
+            cache_dir: tmp_dir.path().join("cache"),
+            ..Context::default()
+        };
+        let server = Server {
+            prefix: prefix.clone(),
+        };
+
+        let plan = Orchestrator::default().plan(&[&server], &ctx).unwrap();
+        assert_eq!(plan.registers.len(), 1);
+        assert!(!plan.registers[0].already_setup);
+        assert_eq!(
+            plan.registers[0].actions,
+            vec![
+                Action::Download {
+                    url: String::from("https://example.com/server-1.0.tar.gz"),
+                    to: ctx
+                        .cached_path(&Url::parse("https://example.com/server-1.0.tar.gz").unwrap())
+                        .unwrap(),
+                },
+                Action::Extract {
+                    archive: String::from("https://example.com/server-1.0.tar.gz"),
+                    to: prefix.join("bin"),
+                },
+                Action::CreateDir {
+                    path: prefix.join("etc"),
+                },
+                Action::WriteFile {
+                    path: prefix.join("etc").join("server.conf"),
+                    size: 10,
+                },
+                Action::SetEnv {
+                    key: String::from("OFFREGISTERS_PLAN_TEST"),
+                    value: String::from("1"),
+                },
+            ]
+        );
+
+        // Nothing happened
+        assert!(!prefix.exists());
+        assert!(std::env::var_os("OFFREGISTERS_PLAN_TEST").is_none());
+
+        assert!(plan.to_string().starts_with("server:\n  download "));
+        let json = plan.to_json().unwrap();
+        assert!(json.contains("\"action\": \"write_file\""));
+        assert_eq!(serde_json::from_str::<Plan>(&json).unwrap(), plan);
+    }
+}
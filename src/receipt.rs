@@ -0,0 +1,321 @@
+use std::collections::BTreeSet;
+use std::ffi::OsString;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use serde::{Deserialize, Serialize};
+
+use crate::context::lock_env;
+use crate::error::{Error, IoContext};
+use crate::fs::mkdirp;
+use crate::manifest::{is_empty_dir, EntryKind, Manifest};
+
+/// How a `Context` helper made a recorded path, so that `Runner::repair`
+/// can make it again
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Source {
+    /// `Context::download`
+    Download { url: String, sha256: Option<String> },
+    /// An entry of what `Context::untar_url` extracted into `to`
+    ArchiveUrl {
+        url: String,
+        sha256: Option<String>,
+        to: PathBuf,
+    },
+    /// An entry of what `Context::untar` extracted into `to`
+    Archive { archive: PathBuf, to: PathBuf },
+    /// `Context::write`, when what it wrote is text. Kept in the receipt, so
+    /// the state dir needs to be as private as what registers write.
+    Text { contents: String },
+}
+
+/// Something an install left behind
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "record", rename_all = "snake_case")]
+pub enum Record {
+    File {
+        path: PathBuf,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        source: Option<Source>,
+    },
+    Directory {
+        path: PathBuf,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        source: Option<Source>,
+    },
+    Symlink {
+        path: PathBuf,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        source: Option<Source>,
+    },
+    /// Kept in the cache, so left alone by `Receipt::replay`
+    Download { url: String, path: PathBuf },
+    /// Set in this process only: `Receipt::replay` restores `previous` in
+    /// the process that replays it, and nothing persistent is undone
+    Env {
+        key: String,
+        previous: Option<String>,
+    },
+}
+
+impl Record {
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            Record::File { path, .. }
+            | Record::Directory { path, .. }
+            | Record::Symlink { path, .. }
+            | Record::Download { path, .. } => Some(path),
+            Record::Env { .. } => None,
+        }
+    }
+
+    /// The files, directories and symlinks an extracted archive created;
+    /// those it `replaced` were there before, so aren't the install's
+    pub fn from_manifest(manifest: &Manifest, source: Option<&Source>) -> Vec<Record> {
+        manifest
+            .entries
+            .iter()
+            .filter(|entry| !entry.replaced)
+            .map(|entry| {
+                let (path, source) = (manifest.root.join(&entry.path), source.cloned());
+                match entry.kind {
+                    EntryKind::Directory => Record::Directory { path, source },
+                    EntryKind::Symlink => Record::Symlink { path, source },
+                    EntryKind::File | EntryKind::Other => Record::File { path, source },
+                }
+            })
+            .collect()
+    }
+
+    /// What made this, if a `Context` helper did
+    pub fn source(&self) -> Option<&Source> {
+        match self {
+            Record::File { source, .. }
+            | Record::Directory { source, .. }
+            | Record::Symlink { source, .. } => source.as_ref(),
+            Record::Download { .. } | Record::Env { .. } => None,
+        }
+    }
+}
+
+/// What installing a register did, in order
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Receipt {
+    pub register: String,
+    pub version: Option<String>,
+    pub records: Vec<Record>,
+}
+
+impl Receipt {
+    /// Undoes the records, newest first. Things already gone are skipped,
+    /// and directories that something else has since been put in are kept.
+    /// Environment variables are only put back in this process.
+    pub fn replay(&self) -> Result<(), Error> {
+        for record in self.records.iter().rev() {
+            let result = match record {
+                Record::File { path, .. } | Record::Symlink { path, .. } => {
+                    std::fs::remove_file(path)
+                }
+                Record::Directory { path, .. } if !is_empty_dir(path) => continue,
+                Record::Directory { path, .. } => std::fs::remove_dir(path),
+                Record::Download { .. } => continue,
+                Record::Env { key, previous } => {
+                    let _env = lock_env();
+                    match previous {
+                        Some(value) => std::env::set_var(key, value),
+                        None => std::env::remove_var(key),
+                    }
+                    continue;
+                }
+            };
+            match result {
+                Err(ref e) if e.kind() == ErrorKind::NotFound => {}
+                r => r.at(record.path().unwrap_or_else(|| Path::new("")))?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Where a `Context` collects records while a register installs
+#[derive(Debug, Default)]
+pub struct Recording {
+    records: Mutex<Vec<Record>>,
+}
+
+impl Recording {
+    pub fn record(&self, record: Record) {
+        if let Ok(mut records) = self.records.lock() {
+            records.push(record);
+        }
+    }
+
+    pub fn take(&self) -> Vec<Record> {
+        match self.records.lock() {
+            Ok(mut records) => std::mem::take(&mut *records),
+            Err(_) => Vec::new(),
+        }
+    }
+}
+
+/// One JSON receipt per register, in a state directory
+#[derive(Clone, Debug)]
+pub struct ReceiptStore {
+    pub dir: PathBuf,
+}
+
+impl ReceiptStore {
+    pub fn new<D: Into<OsString>>(dir: D) -> Self {
+        ReceiptStore {
+            dir: PathBuf::from(dir.into()),
+        }
+    }
+
+    fn path(&self, register: &str) -> PathBuf {
+        self.dir.join(format!("{}.json", register))
+    }
+
+    pub fn load(&self, register: &str) -> Result<Option<Receipt>, Error> {
+        let path = self.path(register);
+        match std::fs::read(&path) {
+            Ok(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).at(&path),
+        }
+    }
+
+    pub fn save(&self, receipt: &Receipt) -> Result<(), Error> {
+        mkdirp(&self.dir)?;
+        let path = self.path(&receipt.register);
+        std::fs::write(&path, serde_json::to_vec_pretty(receipt)?).at(&path)
+    }
+
+    pub fn remove(&self, register: &str) -> Result<(), Error> {
+        let path = self.path(register);
+        match std::fs::remove_file(&path) {
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            r => r.at(&path),
+        }
+    }
+
+    /// Registers with a receipt, sorted
+    pub fn registers(&self) -> Result<BTreeSet<String>, Error> {
+        let mut registers = BTreeSet::new();
+        let dir = match std::fs::read_dir(&self.dir) {
+            Err(ref e) if e.kind() == ErrorKind::NotFound => return Ok(registers),
+            r => r.at(&self.dir)?,
+        };
+        for entry in dir {
+            let path = entry.at(&self.dir)?.path();
+            if path.extension().is_some_and(|ext| ext == "json") {
+                if let Some(stem) = path.file_stem() {
+                    registers.insert(stem.to_string_lossy().into_owned());
+                }
+            }
+        }
+        Ok(registers)
+    }
+
+    /// The register whose receipt records `path`
+    pub fn owner<P: AsRef<Path>>(&self, path: P) -> Result<Option<String>, Error> {
+        let path = path.as_ref();
+        for register in self.registers()? {
+            if let Some(receipt) = self.load(&register)? {
+                if receipt.records.iter().any(|r| r.path() == Some(path)) {
+                    return Ok(Some(receipt.register));
+                }
+            }
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use tempfile::TempDir;
+
+    use crate::manifest::ManifestEntry;
+
+    #[test]
+    fn test_receipt_store() {
+        let tmp_dir: TempDir = tempfile::Builder::new()
+            .prefix(env!("CARGO_PKG_NAME"))
+            .tempdir()
+            .unwrap();
+        let root = tmp_dir.path().join("root");
+        let conf = root.join("etc").join("server.conf");
+        mkdirp(root.join("etc")).unwrap();
+        std::fs::write(&conf, "port = 80\n").unwrap();
+        std::fs::write(root.join("etc").join("other.conf"), "").unwrap();
+
+        let store = ReceiptStore::new(tmp_dir.path().join("state"));
+        assert_eq!(store.load("server").unwrap(), None);
+        let receipt = Receipt {
+            register: String::from("server"),
+            version: Some(String::from("1.0")),
+            records: vec![
+                Record::Directory {
+                    path: root.clone(),
+                    source: None,
+                },
+                Record::Directory {
+                    path: root.join("etc"),
+                    source: None,
+                },
+                Record::File {
+                    path: conf.clone(),
+                    source: Some(Source::Text {
+                        contents: String::from("port = 80\n"),
+                    }),
+                },
+                Record::File {
+                    path: root.join("gone"),
+                    source: None,
+                },
+            ],
+        };
+        store.save(&receipt).unwrap();
+        assert_eq!(store.load("server").unwrap(), Some(receipt.clone()));
+        assert_eq!(store.owner(&conf).unwrap(), Some(String::from("server")));
+        assert_eq!(store.owner(root.join("etc/other.conf")).unwrap(), None);
+
+        receipt.replay().unwrap();
+        assert!(!conf.exists());
+        // Kept for the file nobody recorded
+        assert!(root.join("etc").join("other.conf").exists());
+
+        store.remove("server").unwrap();
+        assert!(store.registers().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_from_manifest() {
+        let entry = |path: &str, replaced: bool| ManifestEntry {
+            path: PathBuf::from(path),
+            kind: EntryKind::File,
+            size: 0,
+            mode: 0o644,
+            digest: None,
+            replaced,
+        };
+        let manifest = Manifest {
+            root: PathBuf::from("/opt/tool"),
+            entries: vec![entry("bin/tool", false), entry("etc/tool.conf", true)],
+        };
+        let source = Source::Archive {
+            archive: PathBuf::from("/tmp/tool.tar"),
+            to: PathBuf::from("/opt/tool"),
+        };
+        assert_eq!(
+            Record::from_manifest(&manifest, Some(&source)),
+            vec![Record::File {
+                path: PathBuf::from("/opt/tool/bin/tool"),
+                source: Some(source),
+            }]
+        );
+    }
+}
@@ -0,0 +1,224 @@
+use std::collections::btree_map::Entry;
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::{Deserialize, Serialize};
+
+use crate::error::Error;
+use crate::OffRegisters;
+
+/// What a host application can know about a register without running it
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Metadata {
+    pub name: String,
+    pub description: String,
+    /// Values of `std::env::consts::OS`, e.g. `linux`; empty for all
+    pub platforms: Vec<String>,
+    pub version: Option<String>,
+    pub dependencies: Vec<String>,
+}
+
+impl Metadata {
+    /// Name, version and dependencies, as the register reports them
+    pub fn of(register: &dyn OffRegisters) -> Self {
+        Metadata {
+            name: register.name().to_string(),
+            version: register.version().map(String::from),
+            dependencies: register
+                .dependencies()
+                .into_iter()
+                .map(String::from)
+                .collect(),
+            ..Metadata::default()
+        }
+    }
+
+    pub fn supports(&self, platform: &str) -> bool {
+        self.platforms.is_empty() || self.platforms.iter().any(|p| p == platform)
+    }
+}
+
+/// The register `Registry::add` just added, to describe it. Name, version
+/// and dependencies come from the register and can't be changed here.
+pub struct Registration<'a> {
+    metadata: &'a mut Metadata,
+}
+
+impl Registration<'_> {
+    pub fn description<S: Into<String>>(self, description: S) -> Self {
+        self.metadata.description = description.into();
+        self
+    }
+
+    /// Values of `std::env::consts::OS`, e.g. `linux`; empty for all
+    pub fn platforms<S: AsRef<str>>(self, platforms: &[S]) -> Self {
+        self.metadata.platforms = platforms.iter().map(|p| p.as_ref().to_string()).collect();
+        self
+    }
+}
+
+/// Registers by name, so that which ones to run can be picked at runtime
+#[derive(Default)]
+pub struct Registry {
+    registers: BTreeMap<String, (Metadata, Box<dyn OffRegisters>)>,
+}
+
+impl Registry {
+    /// Adds `register` under its name, returning a handle to fill in its
+    /// description and platforms
+    pub fn add<R>(&mut self, register: R) -> Result<Registration<'_>, Error>
+    where
+        R: OffRegisters + 'static,
+    {
+        let metadata = Metadata::of(&register);
+        match self.registers.entry(metadata.name.clone()) {
+            Entry::Occupied(entry) => Err(Error::DuplicateRegister(entry.key().clone())),
+            Entry::Vacant(entry) => Ok(Registration {
+                metadata: &mut entry.insert((metadata, Box::new(register))).0,
+            }),
+        }
+    }
+
+    pub fn get(&self, name: &str) -> Option<&dyn OffRegisters> {
+        self.registers.get(name).map(|(_, r)| r.as_ref())
+    }
+
+    pub fn metadata(&self, name: &str) -> Option<&Metadata> {
+        self.registers.get(name).map(|(m, _)| m)
+    }
+
+    /// Every register, by name
+    pub fn iter(&self) -> impl Iterator<Item = (&Metadata, &dyn OffRegisters)> {
+        self.registers.values().map(|(m, r)| (m, r.as_ref()))
+    }
+
+    /// The named registers and everything they depend on, ready for an
+    /// `Orchestrator`
+    pub fn select<S: AsRef<str>>(&self, names: &[S]) -> Result<Vec<&dyn OffRegisters>, Error> {
+        let mut selected: BTreeSet<&str> = BTreeSet::new();
+        let mut pending: Vec<&str> = names.iter().map(AsRef::as_ref).collect();
+        while let Some(name) = pending.pop() {
+            let (name, (metadata, _)) = self
+                .registers
+                .get_key_value(name)
+                .ok_or_else(|| Error::UnknownRegister(name.to_string()))?;
+            if selected.insert(name) {
+                pending.extend(metadata.dependencies.iter().map(String::as_str));
+            }
+        }
+        Ok(selected
+            .into_iter()
+            .filter_map(|name| self.get(name))
+            .collect())
+    }
+
+    /// Every register that supports the platform this runs on, and whose
+    /// dependencies all do too, so that the result can go straight to an
+    /// `Orchestrator`
+    pub fn for_this_platform(&self) -> Vec<&dyn OffRegisters> {
+        let mut supported: BTreeSet<&str> = self
+            .iter()
+            .filter(|(m, _)| m.supports(std::env::consts::OS))
+            .map(|(m, _)| m.name.as_str())
+            .collect();
+        // Dropping one register can leave another without a dependency
+        loop {
+            let unmet: Vec<&str> = supported
+                .iter()
+                .filter(|name| {
+                    self.registers[**name]
+                        .0
+                        .dependencies
+                        .iter()
+                        .any(|d| !supported.contains(d.as_str()))
+                })
+                .cloned()
+                .collect();
+            if unmet.is_empty() {
+                break;
+            }
+            for name in unmet {
+                supported.remove(name);
+            }
+        }
+        supported
+            .into_iter()
+            .filter_map(|name| self.get(name))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::Context;
+
+    struct Named {
+        name: &'static str,
+        dependencies: Vec<&'static str>,
+    }
+
+    impl OffRegisters for Named {
+        fn name(&self) -> &str {
+            self.name
+        }
+        fn dependencies(&self) -> Vec<&str> {
+            self.dependencies.clone()
+        }
+        fn version(&self) -> Option<&str> {
+            Some("1.0")
+        }
+        fn already_setup(&self, _ctx: &Context) -> Result<bool, Error> {
+            Ok(false)
+        }
+        fn install(&self, _ctx: &Context) -> Result<(), Error> {
+            Ok(())
+        }
+        fn uninstall(&self, _ctx: &Context) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    fn named(name: &'static str, dependencies: Vec<&'static str>) -> Named {
+        Named { name, dependencies }
+    }
+
+    #[test]
+    fn test_registry() {
+        let mut registry = Registry::default();
+        registry
+            .add(named("user", vec![]))
+            .unwrap()
+            .description("A user");
+        registry
+            .add(named("postgres", vec!["user"]))
+            .unwrap()
+            .platforms(&["plan9"]);
+        registry.add(named("api", vec!["postgres"])).unwrap();
+        registry.add(named("cache", vec![])).unwrap();
+
+        assert!(matches!(
+            registry.add(named("user", vec![])),
+            Err(Error::DuplicateRegister(ref name)) if name == "user"
+        ));
+
+        let user = registry.metadata("user").unwrap();
+        assert_eq!(user.description, "A user");
+        assert_eq!(user.version.as_deref(), Some("1.0"));
+        assert_eq!(registry.get("api").unwrap().name(), "api");
+
+        let names = |registers: Vec<&dyn OffRegisters>| -> Vec<String> {
+            registers.iter().map(|r| r.name().to_string()).collect()
+        };
+        assert_eq!(
+            names(registry.select(&["api"]).unwrap()),
+            vec!["api", "postgres", "user"]
+        );
+        assert!(matches!(
+            registry.select(&["nginx"]),
+            Err(Error::UnknownRegister(ref name)) if name == "nginx"
+        ));
+        // `api` needs `postgres`, which doesn't run here
+        assert_eq!(names(registry.for_this_platform()), vec!["cache", "user"]);
+    }
+}
@@ -0,0 +1,1123 @@
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::fmt;
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::check::Report;
+use crate::event::{Event, Observers};
+use crate::plan::{Recorder, RegisterPlan, Upgrade};
+use crate::receipt::{Receipt, ReceiptStore, Record, Recording, Source};
+use crate::{Context, Error, OffRegisters};
+
+use url::Url;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    AlreadySetup,
+    PreInstall,
+    Install,
+    PostInstall,
+    Upgrade,
+    Uninstall,
+    Verify,
+    /// Reading and updating the receipt around a repair
+    Repair,
+}
+
+impl fmt::Display for Phase {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Phase::AlreadySetup => "already_setup",
+            Phase::PreInstall => "pre_install",
+            Phase::Install => "install",
+            Phase::PostInstall => "post_install",
+            Phase::Upgrade => "upgrade",
+            Phase::Uninstall => "uninstall",
+            Phase::Verify => "verify",
+            Phase::Repair => "repair",
+        })
+    }
+}
+
+/// Runs one phase of a register
+type PhaseFn = fn(&dyn OffRegisters, &Context) -> Result<(), Error>;
+
+pub type RollbackFn = dyn Fn(&dyn OffRegisters, &Context) -> Result<(), Error> + Send + Sync;
+
+/// What to do when a phase after `already_setup` fails
+#[derive(Default)]
+pub enum Rollback {
+    /// Call `uninstall`
+    #[default]
+    Uninstall,
+    /// Leave things as they are, e.g. to debug the failure
+    Nothing,
+    Custom(Box<RollbackFn>),
+}
+
+#[derive(Debug)]
+pub struct Failure {
+    pub phase: Phase,
+    pub error: Error,
+    /// Whether a rollback ran, and how that went
+    pub rollback: Option<Result<(), Error>>,
+    /// An upgrade failed after the old version was uninstalled, as the
+    /// default `OffRegisters::upgrade` does first, so neither is installed
+    pub uninstalled: bool,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} failed: {}", self.phase, self.error)?;
+        match &self.rollback {
+            Some(Ok(())) => write!(f, " (rolled back)")?,
+            Some(Err(e)) => write!(f, " (rollback failed: {})", e)?,
+            None => {}
+        }
+        if self.uninstalled {
+            write!(f, ", and the old version was already uninstalled")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum Outcome {
+    /// `already_setup` was true, so nothing else ran
+    AlreadySetup,
+    Installed,
+    Upgraded {
+        from: String,
+        to: String,
+    },
+    Uninstalled,
+    Verified(Report),
+    /// What was missing was put back; how `verify` found it afterwards
+    Repaired(Report),
+    Failed(Failure),
+    /// Not run, because the named register it relies on failed
+    Blocked(String),
+}
+
+impl fmt::Display for Outcome {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Outcome::AlreadySetup => write!(f, "already set up"),
+            Outcome::Installed => write!(f, "installed"),
+            Outcome::Upgraded { from, to } => write!(f, "upgraded from {} to {}", from, to),
+            Outcome::Uninstalled => write!(f, "uninstalled"),
+            Outcome::Verified(report) => match report.failed().count() {
+                0 => write!(f, "healthy"),
+                n => write!(f, "{} of {} checks failed", n, report.checks.len()),
+            },
+            Outcome::Repaired(report) => match report.failed().count() {
+                0 => write!(f, "repaired"),
+                n => write!(f, "repaired, but {} checks still fail", n),
+            },
+            Outcome::Failed(failure) => write!(f, "{}", failure),
+            Outcome::Blocked(blocker) => write!(f, "skipped, {} failed", blocker),
+        }
+    }
+}
+
+impl Outcome {
+    /// False for failures, blocked registers, and failed checks
+    pub fn is_ok(&self) -> bool {
+        match self {
+            Outcome::Failed(_) | Outcome::Blocked(_) => false,
+            Outcome::Verified(report) | Outcome::Repaired(report) => report.is_healthy(),
+            _ => true,
+        }
+    }
+}
+
+/// Drives a register through `already_setup`, `pre_install`, `install` and
+/// `post_install`, rolling back if one of the last three fails. Registers
+/// that are set up at another version than they want are upgraded instead.
+/// With a `Context::state_dir`, what the `Context` helpers did is kept as
+/// the register's receipt, which `uninstall` replays and `repair` checks.
+/// Anything done through the free functions in `download`, `archive` and
+/// `fs` is not in the receipt, so `uninstall` has to remove it itself.
+#[derive(Default)]
+pub struct Runner {
+    pub rollback: Rollback,
+    /// Upgrade to older versions too, rather than failing
+    pub allow_downgrade: bool,
+    /// Told when each phase starts and ends, and of everything the
+    /// `Context` helpers do meanwhile
+    pub observers: Observers,
+}
+
+impl Runner {
+    pub fn install(&self, register: &dyn OffRegisters, ctx: &Context) -> Outcome {
+        let ctx = &*self.observed(ctx);
+        let store = match ctx.receipts() {
+            Some(store) => store,
+            None => return self.run_install(register, ctx),
+        };
+        let recording = Arc::new(Recording::default());
+        let ctx = &Context {
+            receipt: Some(recording.clone()),
+            ..ctx.clone()
+        };
+        let outcome = self.run_install(register, ctx);
+        let phase = match outcome {
+            Outcome::Installed => Phase::Install,
+            Outcome::Upgraded { .. } => Phase::Upgrade,
+            _ => return outcome,
+        };
+        match save_receipt(&store, register, recording.take(), phase == Phase::Upgrade) {
+            Ok(()) => outcome,
+            Err(error) => Outcome::Failed(Failure {
+                phase,
+                error,
+                rollback: None,
+                uninstalled: false,
+            }),
+        }
+    }
+
+    fn run_install(&self, register: &dyn OffRegisters, ctx: &Context) -> Outcome {
+        match self.timed(register, Phase::AlreadySetup, ctx, || {
+            register.already_setup(ctx)
+        }) {
+            Ok(true) => return self.upgrade(register, ctx),
+            Ok(false) => {}
+            Err(error) => {
+                return Outcome::Failed(Failure {
+                    phase: Phase::AlreadySetup,
+                    error,
+                    rollback: None,
+                    uninstalled: false,
+                })
+            }
+        }
+
+        match self.install_phases(register, ctx) {
+            Ok(()) => Outcome::Installed,
+            Err((phase, error)) => Outcome::Failed(Failure {
+                phase,
+                error,
+                rollback: self.roll_back(register, ctx),
+                uninstalled: false,
+            }),
+        }
+    }
+
+    /// `pre_install`, `install` and `post_install`, up to the first failure
+    fn install_phases(
+        &self,
+        register: &dyn OffRegisters,
+        ctx: &Context,
+    ) -> Result<(), (Phase, Error)> {
+        let phases: [(Phase, PhaseFn); 3] = [
+            (Phase::PreInstall, |r, ctx| r.pre_install(ctx)),
+            (Phase::Install, |r, ctx| r.install(ctx)),
+            (Phase::PostInstall, |r, ctx| r.post_install(ctx)),
+        ];
+        for (phase, run) in phases.iter() {
+            ctx.log(&format!("{}: {}", register.name(), phase));
+            if let Err(error) = self.timed(register, *phase, ctx, || run(register, ctx)) {
+                ctx.log(&format!("{}: {} failed: {}", register.name(), phase, error));
+                return Err((*phase, error));
+            }
+        }
+        Ok(())
+    }
+
+    /// Fixes a half-broken install, whatever `already_setup` says. If paths
+    /// its receipt lists are missing, or `verify` fails, each missing one
+    /// is made again by the `Context` helper that first made it, with
+    /// downloads coming from the cache, and then `post_install` runs again
+    /// with `Context::repair` set. `pre_install` and `install` don't run, so
+    /// nothing they did outside the helpers is redone. Paths that are still
+    /// there are kept as they are: an edited config can't be told from a
+    /// corrupt one, so fixing the latter is up to `post_install` or the user.
+    pub fn repair(&self, register: &dyn OffRegisters, ctx: &Context) -> Outcome {
+        let ctx = &*self.observed(ctx);
+        let failed = |phase, error| {
+            // Rolling back would uninstall what is left of a working install
+            Outcome::Failed(Failure {
+                phase,
+                error,
+                rollback: None,
+                uninstalled: false,
+            })
+        };
+        let store = ctx.receipts();
+        let missing = match store.as_ref().map(|s| s.load(register.name())) {
+            Some(Ok(receipt)) => receipt.map(|r| missing(&r)).unwrap_or_default(),
+            Some(Err(error)) => return failed(Phase::Repair, error),
+            None => Vec::new(),
+        };
+        let report = match self.timed(register, Phase::Verify, ctx, || register.verify(ctx)) {
+            Ok(report) => report,
+            Err(error) => return failed(Phase::Verify, error),
+        };
+        if missing.is_empty() && report.is_healthy() {
+            ctx.log(&format!("{}: nothing to repair", register.name()));
+            return Outcome::Verified(report);
+        }
+        for record in &missing {
+            if let Some(path) = record.path() {
+                ctx.log(&format!("{}: missing {}", register.name(), path.display()));
+            }
+        }
+        for check in report.failed() {
+            ctx.log(&format!("{}: {} failed", register.name(), check.name));
+        }
+
+        let recording = Arc::new(Recording::default());
+        let repairing = Context {
+            repair: true,
+            receipt: store.as_ref().map(|_| recording.clone()),
+            ..ctx.clone()
+        };
+        let mut extracted = Vec::new();
+        for record in &missing {
+            if let Err(error) = restore(register, &repairing, record, &mut extracted) {
+                return failed(Phase::Repair, error);
+            }
+        }
+        ctx.log(&format!("{}: {}", register.name(), Phase::PostInstall));
+        if let Err(error) = self.timed(register, Phase::PostInstall, &repairing, || {
+            register.post_install(&repairing)
+        }) {
+            return failed(Phase::PostInstall, error);
+        }
+        if let Some(store) = &store {
+            if let Err(error) = save_receipt(store, register, recording.take(), true) {
+                return failed(Phase::Repair, error);
+            }
+        }
+        match self.timed(register, Phase::Verify, ctx, || register.verify(ctx)) {
+            Ok(report) => Outcome::Repaired(report),
+            Err(error) => failed(Phase::Verify, error),
+        }
+    }
+
+    fn upgrade(&self, register: &dyn OffRegisters, ctx: &Context) -> Outcome {
+        let failed = |error| {
+            // Rolling back would uninstall the version that is still working
+            Outcome::Failed(Failure {
+                phase: Phase::Upgrade,
+                error,
+                rollback: None,
+                uninstalled: false,
+            })
+        };
+        let upgrade = match self.pending_upgrade(register, ctx) {
+            Ok(Some(upgrade)) => upgrade,
+            Ok(None) => {
+                ctx.log(&format!("{}: already set up", register.name()));
+                return Outcome::AlreadySetup;
+            }
+            Err(error) => return failed(error),
+        };
+        ctx.log(&format!(
+            "{}: upgrade from {} to {}",
+            register.name(),
+            upgrade.from,
+            upgrade.to
+        ));
+        match self.timed(register, Phase::Upgrade, ctx, || {
+            register.upgrade(ctx, &upgrade.from)
+        }) {
+            Ok(()) => Outcome::Upgraded {
+                from: upgrade.from,
+                to: upgrade.to,
+            },
+            Err(error) => {
+                ctx.log(&format!("{}: upgrade failed: {}", register.name(), error));
+                if !matches!(register.already_setup(ctx), Ok(false)) {
+                    return failed(error);
+                }
+                // The old version is gone, so there's nothing left to
+                // protect from a rollback of what the new one got done
+                ctx.log(&format!(
+                    "{}: {} is no longer installed",
+                    register.name(),
+                    upgrade.from
+                ));
+                Outcome::Failed(Failure {
+                    phase: Phase::Upgrade,
+                    error,
+                    rollback: self.roll_back(register, ctx),
+                    uninstalled: true,
+                })
+            }
+        }
+    }
+
+    /// The upgrade an installed register needs, if both versions are known
+    /// and differ
+    fn pending_upgrade(
+        &self,
+        register: &dyn OffRegisters,
+        ctx: &Context,
+    ) -> Result<Option<Upgrade>, Error> {
+        let to = match register.version() {
+            Some(version) => version.to_string(),
+            None => return Ok(None),
+        };
+        let from = match register.installed_version(ctx)? {
+            Some(version) => version,
+            None => return Ok(None),
+        };
+        match register.compare_versions(&from, &to) {
+            Ordering::Equal => Ok(None),
+            Ordering::Greater if !self.allow_downgrade => Err(Error::Downgrade {
+                register: register.name().to_string(),
+                installed: from,
+                wanted: to,
+            }),
+            _ => Ok(Some(Upgrade { from, to })),
+        }
+    }
+
+    /// Calls `uninstall`, then replays and drops the register's receipt
+    pub fn uninstall(&self, register: &dyn OffRegisters, ctx: &Context) -> Outcome {
+        let ctx = &*self.observed(ctx);
+        ctx.log(&format!("{}: {}", register.name(), Phase::Uninstall));
+        let result = self.timed(register, Phase::Uninstall, ctx, || {
+            register.uninstall(ctx)?;
+            match ctx.receipts() {
+                Some(store) => {
+                    if let Some(receipt) = store.load(register.name())? {
+                        receipt.replay()?;
+                    }
+                    store.remove(register.name())
+                }
+                None => Ok(()),
+            }
+        });
+        match result {
+            Ok(()) => Outcome::Uninstalled,
+            Err(error) => Outcome::Failed(Failure {
+                phase: Phase::Uninstall,
+                error,
+                rollback: None,
+                uninstalled: false,
+            }),
+        }
+    }
+
+    pub fn verify(&self, register: &dyn OffRegisters, ctx: &Context) -> Outcome {
+        let ctx = &*self.observed(ctx);
+        ctx.log(&format!("{}: {}", register.name(), Phase::Verify));
+        match self.timed(register, Phase::Verify, ctx, || register.verify(ctx)) {
+            Ok(report) => {
+                for check in report.failed() {
+                    ctx.log(&format!(
+                        "{}: {} failed: {}",
+                        register.name(),
+                        check.name,
+                        check.error.as_deref().unwrap_or_default()
+                    ));
+                }
+                Outcome::Verified(report)
+            }
+            Err(error) => Outcome::Failed(Failure {
+                phase: Phase::Verify,
+                error,
+                rollback: None,
+                uninstalled: false,
+            }),
+        }
+    }
+
+    /// Runs the install phases as a dry run, collecting what the register
+    /// would do through the `Context` helpers. `already_setup` still runs
+    /// for real, and so does anything a register does behind their back,
+    /// including the free functions in `download`, `archive` and `fs`, so
+    /// registers that use those directly are not safe to plan.
+    /// A pending upgrade is only reported: `upgrade` usually uninstalls
+    /// first, which no helper can hold back.
+    pub fn plan(&self, register: &dyn OffRegisters, ctx: &Context) -> Result<RegisterPlan, Error> {
+        let recorder = Arc::new(Recorder::default());
+        let ctx = Context {
+            plan: Some(recorder.clone()),
+            ..ctx.clone()
+        };
+        let already_setup = register.already_setup(&ctx)?;
+        let mut upgrade = None;
+        if already_setup {
+            upgrade = self.pending_upgrade(register, &ctx)?;
+        } else {
+            register.pre_install(&ctx)?;
+            register.install(&ctx)?;
+            register.post_install(&ctx)?;
+        }
+        Ok(RegisterPlan {
+            name: register.name().to_string(),
+            already_setup,
+            upgrade,
+            actions: recorder.take(),
+        })
+    }
+
+    /// `ctx`, with this runner's observers added to it
+    fn observed<'a>(&self, ctx: &'a Context) -> Cow<'a, Context> {
+        if self.observers.is_empty() {
+            return Cow::Borrowed(ctx);
+        }
+        Cow::Owned(Context {
+            observers: ctx.observers.chain(&self.observers),
+            ..ctx.clone()
+        })
+    }
+
+    /// Runs one phase, telling the observers when it starts and how it ended
+    fn timed<T, F>(
+        &self,
+        register: &dyn OffRegisters,
+        phase: Phase,
+        ctx: &Context,
+        run: F,
+    ) -> Result<T, Error>
+    where
+        F: FnOnce() -> Result<T, Error>,
+    {
+        let name = register.name();
+        ctx.observers.notify(&Event::PhaseStarted {
+            register: name,
+            phase,
+        });
+        let started = Instant::now();
+        let result = run();
+        let elapsed = started.elapsed();
+        match &result {
+            Ok(_) => ctx.observers.notify(&Event::PhaseFinished {
+                register: name,
+                phase,
+                elapsed,
+            }),
+            Err(error) => ctx.observers.notify(&Event::PhaseFailed {
+                register: name,
+                phase,
+                elapsed,
+                error,
+            }),
+        }
+        result
+    }
+
+    fn roll_back(&self, register: &dyn OffRegisters, ctx: &Context) -> Option<Result<(), Error>> {
+        let result = match &self.rollback {
+            // Also undoes whatever the failed install recorded
+            Rollback::Uninstall => register.uninstall(ctx).and_then(|()| match &ctx.receipt {
+                Some(recording) => Receipt {
+                    records: recording.take(),
+                    ..Receipt::default()
+                }
+                .replay(),
+                None => Ok(()),
+            }),
+            Rollback::Nothing => return None,
+            Rollback::Custom(rollback) => rollback(register, ctx),
+        };
+        match &result {
+            Ok(()) => ctx.log(&format!("{}: rolled back", register.name())),
+            Err(e) => ctx.log(&format!("{}: rollback failed: {}", register.name(), e)),
+        }
+        Some(result)
+    }
+}
+
+/// Records of paths that are no longer there; downloads only live in the
+/// cache, so they don't count
+fn missing(receipt: &Receipt) -> Vec<Record> {
+    receipt
+        .records
+        .iter()
+        .filter(|r| !matches!(r, Record::Download { .. }))
+        .filter(|r| {
+            r.path()
+                .is_some_and(|path| path.symlink_metadata().is_err())
+        })
+        .cloned()
+        .collect()
+}
+
+/// Makes a missing record's path again, through the helper that first made
+/// it. Archives are extracted again in full, once, keeping what is there.
+fn restore(
+    register: &dyn OffRegisters,
+    ctx: &Context,
+    record: &Record,
+    extracted: &mut Vec<Source>,
+) -> Result<(), Error> {
+    let path = match record.path() {
+        Some(path) => path,
+        None => return Ok(()),
+    };
+    match record.source() {
+        Some(Source::Download { url, sha256 }) => {
+            ctx.download(&Url::parse(url)?, path, sha256.as_deref())?;
+        }
+        Some(Source::Text { contents }) => ctx.write(path, contents)?,
+        Some(source) if extracted.contains(source) => {}
+        Some(source @ Source::ArchiveUrl { url, sha256, to }) => {
+            ctx.untar_url(&Url::parse(url)?, to, sha256.as_deref())?;
+            extracted.push(source.clone());
+        }
+        Some(source @ Source::Archive { archive, to }) => {
+            ctx.untar(archive, to)?;
+            extracted.push(source.clone());
+        }
+        None if matches!(record, Record::Directory { .. }) => ctx.mkdirp(path)?,
+        None => ctx.log(&format!(
+            "{}: can't restore {}, which no helper made",
+            register.name(),
+            path.display()
+        )),
+    }
+    Ok(())
+}
+
+/// Upgrades and repairs keep the old records as well, since they may not
+/// have replaced everything the install before them left
+fn save_receipt(
+    store: &ReceiptStore,
+    register: &dyn OffRegisters,
+    records: Vec<Record>,
+    merge: bool,
+) -> Result<(), Error> {
+    let mut receipt = Receipt {
+        register: register.name().to_string(),
+        version: register.version().map(String::from),
+        records: Vec::new(),
+    };
+    if merge {
+        if let Some(old) = store.load(register.name())? {
+            receipt.records = old.records;
+        }
+    }
+    for record in records {
+        if !receipt.records.contains(&record) {
+            receipt.records.push(record);
+        }
+    }
+    store.save(&receipt)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Mutex;
+
+    use crate::context::Log;
+
+    struct MemLog(Mutex<Vec<String>>);
+
+    impl Log for MemLog {
+        fn log(&self, message: &str) {
+            self.0.lock().unwrap().push(message.to_string());
+        }
+    }
+
+    /// Records the phases it goes through, failing the one asked to. Set
+    /// up until it is uninstalled.
+    #[derive(Default)]
+    struct Recorder {
+        setup: bool,
+        fail: Option<Phase>,
+        calls: Mutex<Vec<Phase>>,
+        version: Option<&'static str>,
+        installed: Option<&'static str>,
+        /// Upgrade like the default `upgrade`, uninstalling first
+        replaces: bool,
+    }
+
+    impl Recorder {
+        fn call(&self, phase: Phase) -> Result<(), Error> {
+            self.calls.lock().unwrap().push(phase);
+            match self.fail {
+                Some(fail) if fail == phase => Err(Error::msg(format!("{} broke", phase))),
+                _ => Ok(()),
+            }
+        }
+
+        fn calls(&self) -> Vec<Phase> {
+            self.calls.lock().unwrap().clone()
+        }
+    }
+
+    impl OffRegisters for Recorder {
+        fn name(&self) -> &str {
+            "recorder"
+        }
+        fn already_setup(&self, _ctx: &Context) -> Result<bool, Error> {
+            let uninstalled = self.calls().contains(&Phase::Uninstall);
+            self.call(Phase::AlreadySetup)
+                .map(|()| self.setup && !uninstalled)
+        }
+        fn version(&self) -> Option<&str> {
+            self.version
+        }
+        fn installed_version(&self, _ctx: &Context) -> Result<Option<String>, Error> {
+            Ok(self.installed.map(String::from))
+        }
+        fn pre_install(&self, _ctx: &Context) -> Result<(), Error> {
+            self.call(Phase::PreInstall)
+        }
+        fn install(&self, _ctx: &Context) -> Result<(), Error> {
+            self.call(Phase::Install)
+        }
+        fn post_install(&self, _ctx: &Context) -> Result<(), Error> {
+            self.call(Phase::PostInstall)
+        }
+        fn uninstall(&self, _ctx: &Context) -> Result<(), Error> {
+            self.call(Phase::Uninstall)
+        }
+        fn upgrade(&self, _ctx: &Context, _from: &str) -> Result<(), Error> {
+            if self.replaces {
+                self.call(Phase::Uninstall)?;
+            }
+            self.call(Phase::Upgrade)
+        }
+    }
+
+    #[test]
+    fn test_install() {
+        let register = Recorder::default();
+        let outcome = Runner::default().install(&register, &Context::default());
+        assert!(matches!(outcome, Outcome::Installed));
+        assert_eq!(
+            register.calls(),
+            vec![
+                Phase::AlreadySetup,
+                Phase::PreInstall,
+                Phase::Install,
+                Phase::PostInstall
+            ]
+        );
+    }
+
+    #[test]
+    fn test_already_setup() {
+        let register = Recorder {
+            setup: true,
+            ..Recorder::default()
+        };
+        let outcome = Runner::default().install(&register, &Context::default());
+        assert!(matches!(outcome, Outcome::AlreadySetup));
+        assert_eq!(register.calls(), vec![Phase::AlreadySetup]);
+    }
+
+    #[test]
+    fn test_rollback() {
+        let register = Recorder {
+            fail: Some(Phase::Install),
+            ..Recorder::default()
+        };
+        let outcome = Runner::default().install(&register, &Context::default());
+        match outcome {
+            Outcome::Failed(failure) => {
+                assert_eq!(failure.phase, Phase::Install);
+                assert!(matches!(failure.rollback, Some(Ok(()))));
+            }
+            _ => panic!("expected a failure"),
+        }
+        assert_eq!(
+            register.calls(),
+            vec![
+                Phase::AlreadySetup,
+                Phase::PreInstall,
+                Phase::Install,
+                Phase::Uninstall
+            ]
+        );
+
+        let register = Recorder {
+            fail: Some(Phase::PostInstall),
+            ..Recorder::default()
+        };
+        let runner = Runner {
+            rollback: Rollback::Nothing,
+            ..Runner::default()
+        };
+        match runner.install(&register, &Context::default()) {
+            Outcome::Failed(failure) => {
+                assert_eq!(failure.phase, Phase::PostInstall);
+                assert!(failure.rollback.is_none());
+            }
+            _ => panic!("expected a failure"),
+        }
+        assert!(!register.calls().contains(&Phase::Uninstall));
+
+        let register = Recorder {
+            fail: Some(Phase::Install),
+            ..Recorder::default()
+        };
+        let runner = Runner {
+            rollback: Rollback::Custom(Box::new(|_, _| Err(Error::msg("stuck")))),
+            ..Runner::default()
+        };
+        let log = Arc::new(MemLog(Mutex::new(Vec::new())));
+        let ctx = Context {
+            logger: log.clone(),
+            ..Context::default()
+        };
+        assert!(matches!(
+            runner.install(&register, &ctx),
+            Outcome::Failed(Failure {
+                rollback: Some(Err(_)),
+                ..
+            })
+        ));
+        let messages = log.0.lock().unwrap();
+        assert!(messages
+            .iter()
+            .any(|m| m.ends_with("rollback failed: stuck")));
+        assert!(!messages.iter().any(|m| m.ends_with("rolled back")));
+    }
+
+    #[test]
+    fn test_upgrade() {
+        let register = Recorder {
+            setup: true,
+            version: Some("12.1"),
+            installed: Some("11.2"),
+            ..Recorder::default()
+        };
+        match Runner::default().install(&register, &Context::default()) {
+            Outcome::Upgraded { from, to } => {
+                assert_eq!((from.as_str(), to.as_str()), ("11.2", "12.1"))
+            }
+            outcome => panic!("expected an upgrade, got {:?}", outcome),
+        }
+        assert_eq!(register.calls(), vec![Phase::AlreadySetup, Phase::Upgrade]);
+
+        let register = Recorder {
+            setup: true,
+            version: Some("12.1"),
+            installed: Some("12.1"),
+            ..Recorder::default()
+        };
+        let outcome = Runner::default().install(&register, &Context::default());
+        assert!(matches!(outcome, Outcome::AlreadySetup));
+    }
+
+    #[test]
+    fn test_failed_upgrade() {
+        let register = Recorder {
+            setup: true,
+            fail: Some(Phase::Upgrade),
+            version: Some("12.1"),
+            installed: Some("11.2"),
+            ..Recorder::default()
+        };
+        match Runner::default().install(&register, &Context::default()) {
+            Outcome::Failed(failure) => {
+                assert!(failure.rollback.is_none());
+                assert!(!failure.uninstalled);
+            }
+            outcome => panic!("expected a failure, got {:?}", outcome),
+        }
+
+        let register = Recorder {
+            replaces: true,
+            ..register
+        };
+        match Runner::default().install(&register, &Context::default()) {
+            Outcome::Failed(failure) => {
+                assert!(failure.uninstalled);
+                assert!(matches!(failure.rollback, Some(Ok(()))));
+                assert_eq!(
+                    failure.to_string(),
+                    "upgrade failed: upgrade broke (rolled back), \
+                     and the old version was already uninstalled"
+                );
+            }
+            outcome => panic!("expected a failure, got {:?}", outcome),
+        }
+    }
+
+    #[test]
+    fn test_plan_upgrade() {
+        let register = Recorder {
+            setup: true,
+            version: Some("12.1"),
+            installed: Some("11.2"),
+            ..Recorder::default()
+        };
+        let plan = Runner::default()
+            .plan(&register, &Context::default())
+            .unwrap();
+        assert_eq!(
+            plan.upgrade,
+            Some(Upgrade {
+                from: String::from("11.2"),
+                to: String::from("12.1")
+            })
+        );
+        assert!(plan.actions.is_empty());
+        // Nothing was uninstalled to find that out
+        assert_eq!(register.calls(), vec![Phase::AlreadySetup]);
+    }
+
+    #[test]
+    fn test_downgrade() {
+        let register = Recorder {
+            setup: true,
+            version: Some("11.2"),
+            installed: Some("12.1"),
+            ..Recorder::default()
+        };
+        match Runner::default().install(&register, &Context::default()) {
+            Outcome::Failed(failure) => {
+                assert_eq!(failure.phase, Phase::Upgrade);
+                assert!(matches!(failure.error, Error::Downgrade { .. }));
+            }
+            outcome => panic!("expected a failure, got {:?}", outcome),
+        }
+        assert_eq!(register.calls(), vec![Phase::AlreadySetup]);
+
+        let runner = Runner {
+            allow_downgrade: true,
+            ..Runner::default()
+        };
+        let outcome = runner.install(&register, &Context::default());
+        assert!(matches!(outcome, Outcome::Upgraded { .. }));
+    }
+
+    struct Writer {
+        prefix: std::path::PathBuf,
+        env: &'static str,
+        fail: bool,
+    }
+
+    impl OffRegisters for Writer {
+        fn name(&self) -> &str {
+            "writer"
+        }
+        fn already_setup(&self, _ctx: &Context) -> Result<bool, Error> {
+            Ok(false)
+        }
+        fn install(&self, ctx: &Context) -> Result<(), Error> {
+            ctx.write(self.prefix.join("etc").join("writer.conf"), "on\n")?;
+            ctx.set_env(self.env, "1");
+            if self.fail {
+                return Err(Error::msg("writer broke"));
+            }
+            Ok(())
+        }
+        fn uninstall(&self, _ctx: &Context) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_receipts() {
+        let tmp_dir: tempfile::TempDir = tempfile::Builder::new()
+            .prefix(env!("CARGO_PKG_NAME"))
+            .tempdir()
+            .unwrap();
+        let ctx = Context {
+            state_dir: Some(tmp_dir.path().join("state")),
+            ..Context::default()
+        };
+        let prefix = tmp_dir.path().join("prefix");
+        let conf = prefix.join("etc").join("writer.conf");
+        let register = Writer {
+            prefix: prefix.clone(),
+            env: "OFFREGISTERS_RECEIPT_TEST",
+            fail: false,
+        };
+
+        let outcome = Runner::default().install(&register, &ctx);
+        assert!(matches!(outcome, Outcome::Installed));
+        let store = ctx.receipts().unwrap();
+        assert_eq!(store.owner(&conf).unwrap(), Some(String::from("writer")));
+        let receipt = store.load("writer").unwrap().unwrap();
+        assert!(receipt.records.contains(&Record::Directory {
+            path: prefix.clone(),
+            source: None,
+        }));
+
+        let outcome = Runner::default().uninstall(&register, &ctx);
+        assert!(matches!(outcome, Outcome::Uninstalled));
+        assert!(!prefix.exists());
+        assert!(std::env::var_os("OFFREGISTERS_RECEIPT_TEST").is_none());
+        assert_eq!(store.load("writer").unwrap(), None);
+
+        // A failed install is undone by the rollback, and leaves no receipt
+        let register = Writer {
+            prefix: prefix.clone(),
+            env: "OFFREGISTERS_RECEIPT_TEST",
+            fail: true,
+        };
+        let outcome = Runner::default().install(&register, &ctx);
+        assert!(matches!(outcome, Outcome::Failed(_)));
+        assert!(!prefix.exists());
+        assert_eq!(store.load("writer").unwrap(), None);
+    }
+
+    #[test]
+    fn test_repair() {
+        let tmp_dir: tempfile::TempDir = tempfile::Builder::new()
+            .prefix(env!("CARGO_PKG_NAME"))
+            .tempdir()
+            .unwrap();
+        let ctx = Context {
+            state_dir: Some(tmp_dir.path().join("state")),
+            ..Context::default()
+        };
+        let prefix = tmp_dir.path().join("prefix");
+        let conf = prefix.join("etc").join("writer.conf");
+        let register = Writer {
+            prefix: prefix.clone(),
+            env: "OFFREGISTERS_REPAIR_TEST",
+            fail: false,
+        };
+        let runner = Runner::default();
+        assert!(matches!(
+            runner.install(&register, &ctx),
+            Outcome::Installed
+        ));
+
+        // Edits are kept, and so is data the install didn't make
+        std::fs::write(&conf, "off\n").unwrap();
+        let data = prefix.join("data.db");
+        std::fs::write(&data, "rows").unwrap();
+        match runner.repair(&register, &ctx) {
+            Outcome::Verified(report) => assert!(report.is_healthy()),
+            outcome => panic!("expected nothing to repair, got {:?}", outcome),
+        }
+        assert_eq!(std::fs::read(&conf).unwrap(), b"off\n");
+
+        // Only the missing file is put back: `install` doesn't run again
+        std::fs::remove_dir_all(prefix.join("etc")).unwrap();
+        std::env::remove_var("OFFREGISTERS_REPAIR_TEST");
+        let outcome = runner.repair(&register, &ctx);
+        assert!(matches!(outcome, Outcome::Repaired(_)));
+        assert_eq!(outcome.to_string(), "repaired");
+        assert_eq!(std::fs::read(&conf).unwrap(), b"on\n");
+        assert_eq!(std::fs::read(&data).unwrap(), b"rows");
+        assert!(std::env::var_os("OFFREGISTERS_REPAIR_TEST").is_none());
+
+        std::fs::remove_file(&data).unwrap();
+        assert!(matches!(
+            runner.uninstall(&register, &ctx),
+            Outcome::Uninstalled
+        ));
+        assert!(!prefix.exists());
+    }
+
+    /// Extracts a tarball with `Context::untar`
+    struct Unpacker {
+        tarball: std::path::PathBuf,
+        to: std::path::PathBuf,
+    }
+
+    impl OffRegisters for Unpacker {
+        fn name(&self) -> &str {
+            "unpacker"
+        }
+        fn already_setup(&self, _ctx: &Context) -> Result<bool, Error> {
+            Ok(false)
+        }
+        fn install(&self, ctx: &Context) -> Result<(), Error> {
+            ctx.untar(&self.tarball, &self.to).map(|_| ())
+        }
+        fn uninstall(&self, _ctx: &Context) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_repair_archive() {
+        let tmp_dir: tempfile::TempDir = tempfile::Builder::new()
+            .prefix(env!("CARGO_PKG_NAME"))
+            .tempdir()
+            .unwrap();
+        let tarball = tmp_dir.path().join("tool.tar");
+        let mut builder = tar::Builder::new(std::fs::File::create(&tarball).unwrap());
+        for (path, contents) in [("bin/tool", "tool"), ("etc/tool.conf", "on")] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, path, contents.as_bytes())
+                .unwrap();
+        }
+        builder.finish().unwrap();
+
+        let ctx = Context {
+            state_dir: Some(tmp_dir.path().join("state")),
+            ..Context::default()
+        };
+        let to = tmp_dir.path().join("opt");
+        let register = Unpacker {
+            tarball,
+            to: to.clone(),
+        };
+        let runner = Runner::default();
+        assert!(matches!(
+            runner.install(&register, &ctx),
+            Outcome::Installed
+        ));
+
+        std::fs::remove_file(to.join("bin").join("tool")).unwrap();
+        std::fs::write(to.join("etc").join("tool.conf"), "off").unwrap();
+        assert!(matches!(
+            runner.repair(&register, &ctx),
+            Outcome::Repaired(_)
+        ));
+        assert_eq!(std::fs::read(to.join("bin").join("tool")).unwrap(), b"tool");
+        assert_eq!(
+            std::fs::read(to.join("etc").join("tool.conf")).unwrap(),
+            b"off"
+        );
+    }
+
+    #[test]
+    fn test_observers() {
+        let tmp_dir: tempfile::TempDir = tempfile::Builder::new()
+            .prefix(env!("CARGO_PKG_NAME"))
+            .tempdir()
+            .unwrap();
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let mut runner = Runner::default();
+        let seen = events.clone();
+        runner.observers.push(move |event: &Event| {
+            let event = match event {
+                Event::PhaseStarted { phase, .. } => format!("start {}", phase),
+                Event::PhaseFinished { phase, .. } => format!("end {}", phase),
+                Event::PhaseFailed { phase, error, .. } => format!("{} failed: {}", phase, error),
+                Event::FileWritten { path, size } => {
+                    format!(
+                        "wrote {} {}",
+                        path.file_name().unwrap().to_string_lossy(),
+                        size
+                    )
+                }
+                event => format!("{:?}", event),
+            };
+            seen.lock().unwrap().push(event);
+        });
+
+        let register = Writer {
+            prefix: tmp_dir.path().to_path_buf(),
+            env: "OFFREGISTERS_OBSERVER_TEST",
+            fail: true,
+        };
+        runner.install(&register, &Context::default());
+        assert_eq!(
+            *events.lock().unwrap(),
+            vec![
+                "start already_setup",
+                "end already_setup",
+                "start pre_install",
+                "end pre_install",
+                "start install",
+                "wrote writer.conf 3",
+                "install failed: writer broke",
+            ]
+        );
+    }
+}
@@ -0,0 +1,361 @@
+use std::collections::BTreeSet;
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+use crate::error::{Error, IoContext};
+use crate::fs::mkdirp;
+use crate::manifest::{Manifest, ManifestEntry};
+use crate::runner::{Outcome, Runner};
+use crate::{Context, OffRegisters};
+
+/// How a register did in `check_conformance`
+#[derive(Debug, Default)]
+pub struct Conformance {
+    /// Steps that didn't go as they should
+    pub failures: Vec<String>,
+    /// Under the install root after uninstall, but not before install
+    pub leftover: Vec<PathBuf>,
+    /// Under the install root before install, but not after uninstall
+    pub missing: Vec<PathBuf>,
+    /// Changed by installing a second time
+    pub changed: Vec<PathBuf>,
+}
+
+impl Conformance {
+    pub fn passed(&self) -> bool {
+        self.failures.is_empty()
+            && self.leftover.is_empty()
+            && self.missing.is_empty()
+            && self.changed.is_empty()
+    }
+
+    fn expect(&mut self, ok: bool, failure: &str) {
+        if !ok {
+            self.failures.push(failure.to_string());
+        }
+    }
+}
+
+impl fmt::Display for Conformance {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for failure in &self.failures {
+            writeln!(f, "{}", failure)?;
+        }
+        let paths = [
+            ("left behind by uninstall", &self.leftover),
+            ("removed by uninstall", &self.missing),
+            ("changed by a second install", &self.changed),
+        ];
+        for (what, paths) in paths.iter() {
+            for path in paths.iter() {
+                writeln!(f, "{} {}", what, path.display())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Runs `register` through install, install again and uninstall, under a
+/// temporary `Context::root` with its own state dir, and checks that
+/// `already_setup` is false before install and true after, that installing
+/// again, by calling `install` directly and through the runner, changes
+/// nothing, and that uninstall leaves the root as it found it. If `ctx.root` is set, its contents are copied into the temporary
+/// root first, for registers that expect something to be there already; it
+/// is not changed itself. `ctx` provides everything else, e.g. `prefix`,
+/// or `offline` with a `cache_dir` that already has the downloads.
+/// Only what registers do under the root can be seen, so they should go
+/// through the `Context` helpers or `Context::resolve`.
+pub fn check_conformance(register: &dyn OffRegisters, ctx: &Context) -> Result<Conformance, Error> {
+    let tmp_dir = tempfile::Builder::new()
+        .prefix(env!("CARGO_PKG_NAME"))
+        .tempdir()?;
+    let root = tmp_dir.path().join("root");
+    mkdirp(&root)?;
+    if let Some(seed) = &ctx.root {
+        copy_tree(seed, &root)?;
+    }
+    let ctx = Context {
+        root: Some(root.clone()),
+        state_dir: Some(tmp_dir.path().join("state")),
+        ..ctx.clone()
+    };
+    let runner = Runner::default();
+    let mut report = Conformance::default();
+
+    let before = snapshot(&root)?;
+    report.expect(
+        !register.already_setup(&ctx)?,
+        "already_setup was true before install",
+    );
+    match runner.install(register, &ctx) {
+        Outcome::Installed => {}
+        outcome => {
+            report.failures.push(format!("install: {}", outcome));
+            return Ok(report);
+        }
+    }
+    report.expect(
+        register.already_setup(&ctx)?,
+        "already_setup was false after install",
+    );
+
+    let installed = snapshot(&root)?;
+    // The runner stops at `already_setup`, so call `install` itself too
+    if let Err(e) = register.install(&ctx) {
+        report.failures.push(format!("install again: {}", e));
+    }
+    match runner.install(register, &ctx) {
+        Outcome::AlreadySetup => {}
+        outcome => report.failures.push(format!("second install: {}", outcome)),
+    }
+    let again = snapshot(&root)?;
+    report.changed = installed
+        .iter()
+        .filter(|entry| !again.contains(entry))
+        .chain(again.iter().filter(|entry| !installed.contains(entry)))
+        .map(|entry| entry.path.clone())
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect();
+
+    match runner.uninstall(register, &ctx) {
+        Outcome::Uninstalled => {}
+        outcome => report.failures.push(format!("uninstall: {}", outcome)),
+    }
+    report.expect(
+        !register.already_setup(&ctx)?,
+        "already_setup was true after uninstall",
+    );
+    let after = snapshot(&root)?;
+    let paths = |entries: &[ManifestEntry]| -> BTreeSet<PathBuf> {
+        entries.iter().map(|entry| entry.path.clone()).collect()
+    };
+    let (before, after) = (paths(&before), paths(&after));
+    report.leftover = after.difference(&before).cloned().collect();
+    report.missing = before.difference(&after).cloned().collect();
+    Ok(report)
+}
+
+/// `check_conformance` with a default `Context`, panicking with the report
+/// if anything is wrong. See `conformance_test!`.
+pub fn assert_conforms<R: OffRegisters>(register: &R) {
+    let report = match check_conformance(register, &Context::default()) {
+        Ok(report) => report,
+        Err(e) => panic!("{}: {}", register.name(), e),
+    };
+    assert!(report.passed(), "{}:\n{}", register.name(), report);
+}
+
+/// Declares a `#[test]` that runs a register through `assert_conforms`:
+///
+/// ```ignore
+/// liboffregisters::conformance_test!(postgres_conforms, Postgres::default());
+/// ```
+#[macro_export]
+macro_rules! conformance_test {
+    ($name:ident, $register:expr) => {
+        #[test]
+        fn $name() {
+            $crate::testing::assert_conforms(&$register);
+        }
+    };
+}
+
+/// Everything under `root`, with digests so that rewrites show up
+fn snapshot(root: &Path) -> Result<Vec<ManifestEntry>, Error> {
+    let mut paths = BTreeSet::new();
+    walk(root, Path::new(""), &mut paths)?;
+    Ok(Manifest::from_paths(root, &paths, true)?.entries)
+}
+
+/// Copies everything under `from` into `to`, which must exist
+fn copy_tree(from: &Path, to: &Path) -> Result<(), Error> {
+    let mut paths = BTreeSet::new();
+    walk(from, Path::new(""), &mut paths)?;
+    // Sorted, so directories come before what is in them
+    for rel in &paths {
+        let (src, dest) = (from.join(rel), to.join(rel));
+        let file_type = std::fs::symlink_metadata(&src).at(&src)?.file_type();
+        if file_type.is_dir() {
+            std::fs::create_dir(&dest).at(&dest)?;
+        } else if file_type.is_symlink() {
+            #[cfg(unix)]
+            std::os::unix::fs::symlink(std::fs::read_link(&src).at(&src)?, &dest).at(&dest)?;
+        } else {
+            std::fs::copy(&src, &dest).at(&dest)?;
+        }
+    }
+    Ok(())
+}
+
+fn walk(root: &Path, rel: &Path, paths: &mut BTreeSet<PathBuf>) -> Result<(), Error> {
+    let dir = root.join(rel);
+    for entry in std::fs::read_dir(&dir).at(&dir)? {
+        let entry = entry.at(&dir)?;
+        let path = rel.join(entry.file_name());
+        if entry.file_type().at(&dir)?.is_dir() {
+            walk(root, &path, paths)?;
+        }
+        paths.insert(path);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes a config file under `/etc`, optionally leaving a log behind,
+    /// claiming to be out of date however often it is installed, purging
+    /// `/var/lib/service` on uninstall, or appending to its config rather
+    /// than writing it
+    #[derive(Default)]
+    struct Service {
+        leaky: bool,
+        outdated: bool,
+        purge: bool,
+        appends: bool,
+    }
+
+    impl OffRegisters for Service {
+        fn name(&self) -> &str {
+            "service"
+        }
+        fn already_setup(&self, ctx: &Context) -> Result<bool, Error> {
+            Ok(ctx.resolve("/etc/service/service.conf").exists())
+        }
+        fn install(&self, ctx: &Context) -> Result<(), Error> {
+            let conf = ctx.resolve("/etc/service/service.conf");
+            if self.appends && conf.exists() {
+                use std::io::Write;
+
+                std::fs::OpenOptions::new()
+                    .append(true)
+                    .open(&conf)
+                    .and_then(|mut file| file.write_all(b"port = 80\n"))
+                    .at(&conf)?;
+            } else {
+                ctx.write("/etc/service/service.conf", "port = 80\n")?;
+            }
+            ctx.mkdirp("/var/lib/service")
+        }
+        fn post_install(&self, ctx: &Context) -> Result<(), Error> {
+            if self.leaky {
+                let log = ctx.resolve("/var/log/service.log");
+                mkdirp(log.parent().unwrap())?;
+                std::fs::write(&log, "started\n").at(&log)?;
+            }
+            Ok(())
+        }
+        fn upgrade(&self, ctx: &Context, _from: &str) -> Result<(), Error> {
+            ctx.write("/etc/service/service.conf", "port = 81\n")
+        }
+        fn version(&self) -> Option<&str> {
+            Some("2")
+        }
+        fn installed_version(&self, ctx: &Context) -> Result<Option<String>, Error> {
+            if !self.already_setup(ctx)? {
+                return Ok(None);
+            }
+            Ok(Some(String::from(if self.outdated { "1" } else { "2" })))
+        }
+        fn uninstall(&self, ctx: &Context) -> Result<(), Error> {
+            let data = ctx.resolve("/var/lib/service");
+            if self.purge {
+                std::fs::remove_dir_all(&data).at(&data)?;
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_conformance() {
+        assert_conforms(&Service::default());
+
+        let report = check_conformance(
+            &Service {
+                leaky: true,
+                ..Service::default()
+            },
+            &Context::default(),
+        )
+        .unwrap();
+        assert!(!report.passed());
+        assert_eq!(
+            report.leftover,
+            vec![
+                PathBuf::from("var"),
+                PathBuf::from("var/log"),
+                PathBuf::from("var/log/service.log")
+            ]
+        );
+        assert!(report.failures.is_empty());
+
+        // Always out of date, so the second install upgrades, and rewrites
+        // the config while at it
+        let report = check_conformance(
+            &Service {
+                outdated: true,
+                ..Service::default()
+            },
+            &Context::default(),
+        )
+        .unwrap();
+        assert_eq!(report.failures.len(), 1);
+        assert!(report.failures[0].starts_with("second install: upgraded"));
+        assert_eq!(
+            report.changed,
+            vec![PathBuf::from("etc/service/service.conf")]
+        );
+
+        // Installing twice doubles the config
+        let report = check_conformance(
+            &Service {
+                appends: true,
+                ..Service::default()
+            },
+            &Context::default(),
+        )
+        .unwrap();
+        assert!(report.failures.is_empty(), "{}", report);
+        assert_eq!(
+            report.changed,
+            vec![PathBuf::from("etc/service/service.conf")]
+        );
+    }
+
+    #[test]
+    fn test_conformance_seeded() {
+        let seed = tempfile::Builder::new()
+            .prefix(env!("CARGO_PKG_NAME"))
+            .tempdir()
+            .unwrap();
+        let data = seed.path().join("var").join("lib").join("service");
+        mkdirp(&data).unwrap();
+        std::fs::write(data.join("db"), "rows").unwrap();
+        let ctx = Context {
+            root: Some(seed.path().to_path_buf()),
+            ..Context::default()
+        };
+
+        let report = check_conformance(&Service::default(), &ctx).unwrap();
+        assert!(report.passed(), "{}", report);
+
+        // Takes the data that was there before install with it
+        let purge = Service {
+            purge: true,
+            ..Service::default()
+        };
+        let report = check_conformance(&purge, &ctx).unwrap();
+        assert_eq!(
+            report.missing,
+            vec![
+                PathBuf::from("var/lib/service"),
+                PathBuf::from("var/lib/service/db")
+            ]
+        );
+        assert!(data.join("db").exists());
+    }
+
+    conformance_test!(test_conformance_macro, Service::default());
+}
@@ -0,0 +1,67 @@
+use std::cmp::Ordering;
+
+/// Orders version strings such as `11.2`, `12.1-3` or `1.0rc1`: runs of
+/// digits compare as numbers, anything else as text, and a version that
+/// runs out of parts first is the older one unless the other continues
+/// with text, as in a pre-release. Registers with stranger schemes can
+/// override `OffRegisters::compare_versions`.
+pub fn compare(a: &str, b: &str) -> Ordering {
+    let (a, b) = (parts(a), parts(b));
+    for (x, y) in a.iter().zip(b.iter()) {
+        let ordering = match (x.parse::<u64>(), y.parse::<u64>()) {
+            (Ok(x), Ok(y)) => x.cmp(&y),
+            // Numbers sort after text, so `1.0` is newer than `1.rc1`
+            (Ok(_), Err(_)) => Ordering::Greater,
+            (Err(_), Ok(_)) => Ordering::Less,
+            (Err(_), Err(_)) => x.cmp(y),
+        };
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    // `1.0` is newer than `1.0rc1`, but older than `1.0.1`
+    let prerelease = |part: Option<&&str>| part.is_some_and(|p| p.parse::<u64>().is_err());
+    match a.len().cmp(&b.len()) {
+        Ordering::Greater if prerelease(a.get(b.len())) => Ordering::Less,
+        Ordering::Less if prerelease(b.get(a.len())) => Ordering::Greater,
+        ordering => ordering,
+    }
+}
+
+/// Splits on punctuation, and between digits and letters
+fn parts(version: &str) -> Vec<&str> {
+    let mut parts = Vec::new();
+    for segment in version.split(|c: char| !c.is_ascii_alphanumeric()) {
+        let mut start = 0;
+        let bytes = segment.as_bytes();
+        for i in 1..bytes.len() {
+            if bytes[i].is_ascii_digit() != bytes[i - 1].is_ascii_digit() {
+                parts.push(&segment[start..i]);
+                start = i;
+            }
+        }
+        if start < segment.len() {
+            parts.push(&segment[start..]);
+        }
+    }
+    parts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_compare() {
+        assert_eq!(compare("11.2", "12.1"), Ordering::Less);
+        assert_eq!(compare("12.10", "12.9"), Ordering::Greater);
+        assert_eq!(compare("12.1", "12.1"), Ordering::Equal);
+        assert_eq!(compare("1.0", "1.0.1"), Ordering::Less);
+        assert_eq!(compare("2.4.1-3", "2.4.1-12"), Ordering::Less);
+        assert_eq!(compare("1.0rc1", "1.0rc2"), Ordering::Less);
+        assert_eq!(compare("1.0.0", "1.0.rc1"), Ordering::Greater);
+        assert_eq!(compare("1.0rc1", "1.0"), Ordering::Less);
+        assert_eq!(compare("1.0", "1.0-beta"), Ordering::Greater);
+        assert_eq!(compare("v3", "v3"), Ordering::Equal);
+    }
+}